@@ -0,0 +1,60 @@
+use rust_randomx::{Context, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::hashers::keccak_hasher;
+use crate::nonce;
+
+// bench subcommand. hashes like the compute units do, including nonce derivation, but all threads share
+// one dataset so benchmarking many threads doesn't need 2 GB each.
+
+fn bench_thread(context: Arc<Context>, thread_idx: u32, stop: Arc<AtomicBool>) -> u64 {
+    let thread_id = format!("bench_{}", thread_idx);
+    let mut hasher = Hasher::new(context);
+    let mut counter = 0u64;
+    hasher.hash_first(&nonce::derive("bench", &thread_id, &0, &counter));
+    while !stop.load(Relaxed) {
+        counter += 1;
+        hasher.hash_next(&nonce::derive("bench", &thread_id, &0, &counter));
+    }
+    counter
+}
+
+// returns the process exit code
+pub fn run(seconds: u64, threads: u32, light: bool) -> i32 {
+    if threads < 1 || seconds < 1 {
+        eprintln!("bench needs at least one thread and one second");
+        return 2;
+    }
+    let mode = match light {
+        true => "light",
+        false => "fast",
+    };
+
+    println!("initializing randomx in {} mode ...", mode);
+    let init_start = Instant::now();
+    let context = Arc::new(Context::new(&keccak_hasher("randomx bench"), !light));
+    println!("initialized in {:.1}s", init_start.elapsed().as_secs_f64());
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let hash_start = Instant::now();
+    let handles: Vec<thread::JoinHandle<u64>> = (0..threads)
+        .map(|i| {
+            let (context, stop) = (Arc::clone(&context), Arc::clone(&stop));
+            thread::spawn(move || bench_thread(context, i, stop))
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(seconds));
+    stop.swap(true, Relaxed);
+
+    let hashes: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap_or(0)).collect();
+    let elapsed = hash_start.elapsed().as_secs_f64();
+    for (i, h) in hashes.iter().enumerate() {
+        println!("thread {:>3}: {:>10.1} H/s", i, *h as f64 / elapsed);
+    }
+    let total: u64 = hashes.iter().sum();
+    println!("total     : {:>10.1} H/s over {:.1}s with {} threads in {} mode", total as f64 / elapsed, elapsed, threads, mode);
+    0
+}
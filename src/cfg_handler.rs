@@ -1,72 +1,449 @@
 use serde::{ Deserialize, Serialize };
-use serde_json;
+use serde_json::{self, Value};
 use std::cmp::PartialEq;
-use std::fs::File;
-use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
 
-use crate::LOG_PATH;
-use crate::RUNTME_CFG_PATH;
-use crate::SETUP_CFG_PATH;
+use crate::compute_units::RegistryCfg;
+use crate::error::Error;
+use crate::paths;
+use crate::keyblock::{KeyBlockSchedule, KeyBlockSourceCfg, RpcPolicy};
+use crate::precompute::PrecomputeCfg;
 use crate::puzzle::PuzzleType;
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+// SINGLE: one fast mode dataset per thread, keyed by thread id
+// MULTI: one fast mode dataset per key block shared by all threads, each thread runs its own vm
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum RXThreading {
+    #[default]
     SINGLE,
     MULTI,
 }
 
+// configs are toml if the file ends in .toml, json otherwise. the setup config is layered, lowest precedence
+// first:
+//   1. field defaults
+//   2. the built-in profile named by profile, if any
+//   3. the [profiles.<name>] table of the same name in the config file, e.g., to tweak or add a profile
+//   4. the top level fields of the config file
+//   5. RANDOMX_<FIELD> env vars, with __ between nested fields, e.g., RANDOMX_RPC__TIMEOUT_MS=5000. string
+//      fields take the value as is, string lists a comma separated list or json, e.g., RANDOMX_THREAD_IDS=0x..,0x..,
+//      and everything else json.
+// RANDOMX_PROFILE selects the profile like any other field.
+
+// unknown fields are ignored when loading so older and newer configs still load. check-config flags them.
+pub fn setup_cfg_fields() -> Vec<String> {
+    match RandomxCfg::reference() {
+        Value::Object(fields) => fields.keys().cloned().collect(),
+        _ => vec![],
+    }
+}
+
+pub const ENV_PREFIX: &str = "RANDOMX_";
+pub const PROFILES: [&str; 3] = ["devnet", "testnet", "mainnet"];
+
+// chain endpoint, key block schedule and difficulty defaults. devnet expects a local node.
+pub fn builtin_profile(name: &str) -> Option<Value> {
+    let (uri, offset, delay, difficulty) = match name {
+        "devnet" => ("http://127.0.0.1:8545", 64, 8, 8),
+        "testnet" => ("https://api.calibration.node.glif.io/rpc/v1", 512, 32, 12),
+        "mainnet" => ("https://api.node.glif.io/rpc/v1", 2_048, 64, 16),
+        _ => { return None; }
+    };
+    Some(serde_json::json!({
+        "key_blockchain_uri": uri,
+        "key_block_schedule": {"offset": offset, "delay": delay},
+        "difficulty": difficulty,
+    }))
+}
+
+// reads a config file into a json value, whatever its format
+pub fn read_cfg_value(path: &str) -> Result<Value, Error> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => { return Err(Error::io(format!("can't read config {}", path), e)); }
+    };
+    let is_toml = Path::new(path).extension().is_some_and(|ext| ext == "toml");
+    let value = match is_toml {
+        true => toml::from_str::<toml::Value>(&content).map_err(|e| e.to_string()).and_then(|v| serde_json::to_value(v).map_err(|e| e.to_string())),
+        false => serde_json::from_str::<Value>(&content).map_err(|e| e.to_string()),
+    };
+    match value {
+        Ok(v) if v.is_object() => Ok(v),
+        Ok(_) => Err(Error::config(path, "expected a table of fields")),
+        Err(e) => Err(Error::config(path, format!("can't parse: {}", e))),
+    }
+}
+
+// overlay wins, tables are merged field by field
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => { base.insert(k, v); }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// env vars for unknown fields, e.g., RANDOMX_DATA_DIR for the command line, are left alone. reference has the
+// fields of the config and their types.
+fn apply_env(value: &mut Value, env: &[(String, String)], reference: &Value) {
+    for (key, raw) in env {
+        let field_path: Vec<String> = match key.strip_prefix(ENV_PREFIX) {
+            Some(f) => f.to_lowercase().split("__").map(|s| s.to_string()).collect(),
+            None => continue,
+        };
+        if reference.get(&field_path[0]).is_none() {
+            continue;
+        }
+        let mut target = &mut *value;
+        let mut field_type = Some(reference);
+        for field in &field_path {
+            if !target.is_object() {
+                *target = Value::Object(serde_json::Map::new());
+            }
+            target = target.as_object_mut().unwrap().entry(field.clone()).or_insert(Value::Null);
+            field_type = field_type.and_then(|t| t.get(field));
+        }
+        *target = env_value(field_type, raw);
+    }
+}
+
+// nested fields of tagged enums, e.g., key_block_source, only have the reference's variant. for anything
+// the reference doesn't know, json is tried first.
+fn env_value(field_type: Option<&Value>, raw: &str) -> Value {
+    match field_type {
+        Some(Value::String(_)) => Value::String(raw.to_string()),
+        Some(Value::Array(items)) if items.first().is_some_and(Value::is_string) && !raw.trim_start().starts_with('[') => {
+            Value::Array(raw.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).map(|s| Value::String(s.to_string())).collect())
+        }
+        _ => serde_json::from_str(raw).unwrap_or(Value::String(raw.to_string())),
+    }
+}
+
+// the setup config as a json value with all layers applied
+pub fn layered_value(path: &str, env: &[(String, String)]) -> Result<Value, Error> {
+    layered(path, env, &RandomxCfg::reference(), builtin_profile)
+}
+
+// reference has the fields of the config and their types, builtin the built-in profile layer of a name
+fn layered(path: &str, env: &[(String, String)], reference: &Value, builtin: fn(&str) -> Option<Value>) -> Result<Value, Error> {
+    let mut file_value = read_cfg_value(path)?;
+    let mut profiles = file_value.as_object_mut().and_then(|f| f.remove("profiles")).unwrap_or(Value::Null);
+    apply_env(&mut file_value, env, reference);
+
+    let mut value = Value::Object(serde_json::Map::new());
+    if let Some(name) = file_value.get("profile").filter(|p| !p.is_null()) {
+        let name = match name.as_str() {
+            Some(n) => n,
+            None => { return Err(Error::config(path, format!("profile {} is not a name", name))); }
+        };
+        let layers: Vec<Value> = builtin(name).into_iter().chain(profiles.get_mut(name).map(Value::take)).collect();
+        if layers.is_empty() {
+            return Err(Error::config(path, format!("unknown profile {}. use one of {} or define it in [profiles.{}].", name, PROFILES.join(", "), name)));
+        }
+        for layer in layers {
+            merge(&mut value, layer);
+        }
+    }
+    merge(&mut value, file_value);
+    Ok(value)
+}
+
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct RandomxCfg {
+    // devnet, testnet, mainnet or one defined in [profiles.<name>]
+    #[serde(default)]
+    pub profile: Option<String>,
     pub num_cores: u32,
     pub threads_per_core: u32,
+    // compute unit ids, see compute_units. thread_ids_path takes precedence.
+    #[serde(default)]
+    pub thread_ids: Vec<String>,
+    #[serde(default)]
+    pub thread_ids_path: Option<String>,
+    // on-chain compute unit registry, takes precedence over thread_ids
+    #[serde(default)]
+    pub registry: Option<RegistryCfg>,
+    pub peer_id: String,
+    #[serde(default)]
     pub keypair: String,
+    #[serde(default)]
+    pub keypair_path: Option<String>,
     pub thread_model: RXThreading,
     pub puzzle: PuzzleType,
     pub difficulty: u32,
     pub key_blockchain_uri: String,
+    #[serde(default)]
+    pub key_block_source: Option<KeyBlockSourceCfg>,
+    #[serde(default)]
+    pub key_block_schedule: KeyBlockSchedule,
+    #[serde(default)]
+    pub rpc: RpcPolicy,
+    // unix socket path for the control api, disabled if not set
+    #[serde(default)]
+    pub control_socket: Option<String>,
+    // localhost port for the prometheus /metrics endpoint, disabled if not set
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+    #[serde(default)]
+    pub precompute: PrecomputeCfg,
+    // error, warn, info, debug or trace. defaults to debug.
+    #[serde(default)]
+    pub log_level: Option<String>,
 }
 
 impl RandomxCfg {
-    pub fn from_file() -> Result<Self, ()> {
-        let file = File::open(SETUP_CFG_PATH).unwrap();
-        let reader = BufReader::new(file);
-        let cfg: RandomxCfg = serde_json::from_reader(reader).unwrap();
-        if cfg.thread_model != RXThreading::SINGLE {
-            log::error!("invalid thread model. only single is currently implemented.");
-            panic!("{}", format!("invalid thread model config. see log {}.", LOG_PATH));
-        }
-        if cfg.puzzle != PuzzleType::ZEROS {
-            log::error!("invalid puzzle model. only leading zeros is currently implemented.");
-            panic!("{}", format!("invalid puzzle specified. see log {}", LOG_PATH));
-        }
-        if cfg.num_cores < 1 || cfg.threads_per_core < 1 {
-            log::error!("invalid capacity allocation. check you core and thread counts.");
-            panic!(
-                "{}",
-                format!(
-                    "invalid capacity allocation provided. see {} and log path {}",
-                    SETUP_CFG_PATH,
-                    LOG_PATH
-                )
-            );
-        }
+    // every field set, optional ones included, as json. it's a struct literal so new fields can't be left
+    // out, which keeps setup_cfg_fields and the env var types in sync with the struct.
+    fn reference() -> Value {
+        let cfg = RandomxCfg {
+            profile: Some(String::new()),
+            num_cores: 1,
+            threads_per_core: 1,
+            thread_ids: vec![String::new()],
+            thread_ids_path: Some(String::new()),
+            registry: Some(RegistryCfg { contract: String::new(), uri: Some(String::new()), refresh_secs: 0 }),
+            peer_id: String::new(),
+            keypair: String::new(),
+            keypair_path: Some(String::new()),
+            thread_model: RXThreading::default(),
+            puzzle: PuzzleType::default(),
+            difficulty: 1,
+            key_blockchain_uri: String::new(),
+            key_block_source: Some(KeyBlockSourceCfg::Eth { uri: String::new() }),
+            key_block_schedule: KeyBlockSchedule::default(),
+            rpc: RpcPolicy { fallback_uris: vec![String::new()], ..RpcPolicy::default() },
+            control_socket: Some(String::new()),
+            metrics_port: Some(0),
+            precompute: PrecomputeCfg { memory_budget_mb: Some(0), ..PrecomputeCfg::default() },
+            log_level: Some(String::new()),
+        };
+        serde_json::to_value(cfg).unwrap_or(Value::Null)
+    }
+
+    pub fn from_file() -> Result<Self, Error> {
+        RandomxCfg::load(&paths().setup_cfg)
+    }
+
+    // with the RANDOMX_* env vars of the process
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let env: Vec<(String, String)> = std::env::vars().collect();
+        RandomxCfg::load_with_env(path, &env)
+    }
 
+    pub fn load_with_env(path: &str, env: &[(String, String)]) -> Result<Self, Error> {
+        let cfg: RandomxCfg = match serde_json::from_value(layered_value(path, env)?) {
+            Ok(c) => c,
+            Err(e) => { return Err(Error::config(path, e.to_string())); }
+        };
+        if let Err(e) = cfg.validate() {
+            return Err(Error::config(path, e));
+        }
         Ok(cfg)
     }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.problems().into_iter().next() {
+            Some(p) => Err(p),
+            None => Ok(()),
+        }
+    }
+
+    // everything validate would reject, not just the first
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !self.puzzle.valid_difficulty(&self.difficulty) {
+            problems.push(format!("invalid difficulty {} for puzzle {:?}. zeros takes 1 to 256 leading zero bits, comp takes a non-zero number of expected hashes.", self.difficulty, self.puzzle));
+        }
+        if self.num_cores < 1 || self.threads_per_core < 1 {
+            problems.push("invalid capacity allocation. check you core and thread counts.".to_string());
+        }
+        if self.keypair.is_empty() && self.keypair_path.is_none() {
+            problems.push("no keypair provided. set either keypair or keypair_path.".to_string());
+        }
+        let schedule = &self.key_block_schedule;
+        if schedule.offset < 1 || schedule.delay < 1 || schedule.offset % schedule.delay != 0 {
+            problems.push(format!("invalid key block schedule, offset {} needs to be a non-zero multiple of delay {}.", schedule.offset, schedule.delay));
+        }
+        if let Some(ref level) = self.log_level {
+            if log::LevelFilter::from_str(level).is_err() {
+                problems.push(format!("invalid log level {}. use error, warn, info, debug or trace.", level));
+            }
+        }
+        problems
+    }
+
+    pub fn log_level(&self) -> log::LevelFilter {
+        match self.log_level {
+            Some(ref level) => log::LevelFilter::from_str(level).unwrap_or(log::LevelFilter::Debug),
+            None => log::LevelFilter::Debug,
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct RuntimeCfg {
     pub deallocated_threads: u32,
     operator_update: i64,
 }
 
 impl RuntimeCfg {
-    pub fn from_file() -> Result<Self, ()> {
-        let file = File::open(RUNTME_CFG_PATH).unwrap();
-        let reader = BufReader::new(file);
-        let cfg: RuntimeCfg = serde_json::from_reader(reader).unwrap();
-        Ok(cfg)
+    pub fn from_file() -> Result<Self, Error> {
+        RuntimeCfg::load(&paths().runtime_cfg)
+    }
+
+    // with the RANDOMX_* env vars of the process. a torn file, e.g., read while Nox is mid-write, fails to
+    // parse rather than panicking.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let env: Vec<(String, String)> = std::env::vars().collect();
+        RuntimeCfg::load_with_env(path, &env)
+    }
+
+    // layered like the setup config. built-in profiles have no runtime fields, so only the file's
+    // [profiles.<name>] table, picked by its profile field or RANDOMX_PROFILE, applies.
+    pub fn load_with_env(path: &str, env: &[(String, String)]) -> Result<Self, Error> {
+        let mut reference = serde_json::to_value(RuntimeCfg { deallocated_threads: 0, operator_update: 0 }).unwrap_or(Value::Null);
+        if let Some(fields) = reference.as_object_mut() {
+            fields.insert("profile".to_string(), Value::String(String::new()));
+        }
+        let builtin = |name: &str| PROFILES.contains(&name).then(|| Value::Object(serde_json::Map::new()));
+        let mut value = layered(path, env, &reference, builtin)?;
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("profile");
+        }
+        match serde_json::from_value(value) {
+            Ok(c) => Ok(c),
+            Err(e) => Err(Error::config(path, e.to_string())),
+        }
+    }
+
+    // at least one compute unit needs to stay allocated
+    pub fn validate(&self, max_threads: u32) -> Result<(), String> {
+        if self.deallocated_threads >= max_threads {
+            return Err(format!("can't deallocate {} of {} compute units", self.deallocated_threads, max_threads));
+        }
+        if self.operator_update < 0 {
+            return Err(format!("invalid operator_update {}", self.operator_update));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn layered_cfg_test() {
+        let dir = std::env::temp_dir().join(format!("randomx_layered_cfg_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let toml_path = dir.join("randomx_cfg.toml");
+        let toml_path = toml_path.to_str().unwrap();
+        fs::write(toml_path, r#"
+            profile = "testnet"
+            num_cores = 2
+            threads_per_core = 1
+            peer_id = "12D3KooWRF2opN6PWhi13KfJKFpKQ5uoVe6TL8VuGnLRagjpGfmV"
+            keypair = "2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3"
+            thread_model = "single"
+            puzzle = "zeros"
+            difficulty = 14
+
+            [rpc]
+            retries = 1
+
+            [profiles.testnet.key_block_schedule]
+            delay = 64
+        "#).unwrap();
+
+        // profile defaults, the file's profile table and its fields
+        let cfg = RandomxCfg::load_with_env(toml_path, &[]).unwrap();
+        assert_eq!(cfg.key_blockchain_uri, "https://api.calibration.node.glif.io/rpc/v1");
+        assert_eq!(cfg.key_block_schedule, KeyBlockSchedule { offset: 512, delay: 64 });
+        assert_eq!(cfg.difficulty, 14);
+        assert_eq!((cfg.rpc.retries, cfg.rpc.timeout_ms), (1, RpcPolicy::default().timeout_ms));
+
+        // env vars win, nested fields included, and select the profile
+        let overrides = env(&[
+            ("RANDOMX_PROFILE", "mainnet"),
+            ("RANDOMX_DIFFICULTY", "20"),
+            ("RANDOMX_RPC__TIMEOUT_MS", "5000"),
+            ("RANDOMX_LOG_LEVEL", "info"),
+            ("RANDOMX_DATA_DIR", "/srv/randomx"),
+        ]);
+        let cfg = RandomxCfg::load_with_env(toml_path, &overrides).unwrap();
+        assert_eq!(cfg.key_blockchain_uri, "https://api.node.glif.io/rpc/v1");
+        assert_eq!(cfg.key_block_schedule, KeyBlockSchedule::default());
+        assert_eq!(cfg.difficulty, 20);
+        assert_eq!((cfg.rpc.retries, cfg.rpc.timeout_ms), (1, 5_000));
+        assert_eq!(cfg.log_level(), log::LevelFilter::Info);
+
+        // fields that aren't in the file take the struct's type
+        let overrides = env(&[
+            ("RANDOMX_THREAD_IDS", "1"),
+            ("RANDOMX_KEYPAIR_PATH", "42"),
+            ("RANDOMX_METRICS_PORT", "9100"),
+            ("RANDOMX_RPC__FALLBACK_URIS", "http://a:8545, http://b:8545"),
+        ]);
+        let cfg = RandomxCfg::load_with_env(toml_path, &overrides).unwrap();
+        assert_eq!(cfg.thread_ids, vec!["1".to_string()]);
+        assert_eq!(cfg.keypair_path.as_deref(), Some("42"));
+        assert_eq!(cfg.metrics_port, Some(9_100));
+        assert_eq!(cfg.rpc.fallback_uris, vec!["http://a:8545".to_string(), "http://b:8545".to_string()]);
+        let cfg = RandomxCfg::load_with_env(toml_path, &env(&[("RANDOMX_THREAD_IDS", r#"["0x1","0x2"]"#)])).unwrap();
+        assert_eq!(cfg.thread_ids.len(), 2);
+
+        assert!(matches!(RandomxCfg::load_with_env(toml_path, &env(&[("RANDOMX_PROFILE", "moonnet")])), Err(Error::Config { .. })));
+        assert!(matches!(RandomxCfg::load_with_env(toml_path, &env(&[("RANDOMX_DIFFICULTY", "0")])), Err(Error::Config { .. })));
+
+        // json is still accepted
+        let json_path = dir.join("randomx_cfg.json");
+        let json_path = json_path.to_str().unwrap();
+        fs::write(json_path, serde_json::to_string(&cfg).unwrap()).unwrap();
+        assert_eq!(RandomxCfg::load_with_env(json_path, &[]).unwrap(), cfg);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn layered_runtime_cfg_test() {
+        let dir = std::env::temp_dir().join(format!("randomx_layered_runtime_cfg_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("runtime_cfg.toml");
+        let path = path.to_str().unwrap();
+        fs::write(path, r#"
+            deallocated_threads = 1
+            operator_update = 1694962278
+
+            [profiles.devnet]
+            deallocated_threads = 2
+        "#).unwrap();
+
+        let cfg = RuntimeCfg::load_with_env(path, &[]).unwrap();
+        assert_eq!((cfg.deallocated_threads, cfg.operator_update), (1, 1_694_962_278));
+
+        // the profile table applies below the file's fields, env vars win
+        let cfg = RuntimeCfg::load_with_env(path, &env(&[("RANDOMX_PROFILE", "devnet"), ("RANDOMX_DIFFICULTY", "20")])).unwrap();
+        assert_eq!(cfg.deallocated_threads, 1);
+        let cfg = RuntimeCfg::load_with_env(path, &env(&[("RANDOMX_PROFILE", "mainnet"), ("RANDOMX_DEALLOCATED_THREADS", "3")])).unwrap();
+        assert_eq!(cfg.deallocated_threads, 3);
+
+        fs::write(path, "operator_update = 1694962278\n[profiles.devnet]\ndeallocated_threads = 2\n").unwrap();
+        assert_eq!(RuntimeCfg::load_with_env(path, &env(&[("RANDOMX_PROFILE", "devnet")])).unwrap().deallocated_threads, 2);
+        assert!(matches!(RuntimeCfg::load_with_env(path, &env(&[("RANDOMX_PROFILE", "moonnet")])), Err(Error::Config { .. })));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,157 @@
+use crossbeam::channel::Sender;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::ffi::OsStr;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cfg_handler::RuntimeCfg;
+use crate::state::SharedState;
+
+// watches the runtime config with inotify instead of polling it. we watch the parent dir rather than the
+// file so writers can replace it with an atomic rename, and only read once writes settled for the debounce
+// period. invalid updates are logged and skipped, the current allocation stays in place.
+
+pub const RUNTIME_CFG_DEBOUNCE: u32 = 250; // in millis
+
+pub struct RuntimeCfgWatcher {
+    handle: thread::JoinHandle<()>,
+    // dropping it wakes the watcher up
+    wake: UnixStream,
+}
+
+impl RuntimeCfgWatcher {
+    // stops the watcher and waits for it
+    pub fn stop(self) -> thread::Result<()> {
+        drop(self.wake);
+        self.handle.join()
+    }
+}
+
+// updates are validated against the current number of compute units
+pub fn spawn_runtime_cfg_watcher(path: &str, tx: Sender<RuntimeCfg>, state: SharedState) -> Result<RuntimeCfgWatcher, String> {
+    let file_path = Path::new(path);
+    let (dir, file_name) = match (file_path.parent(), file_path.file_name()) {
+        (Some(d), Some(f)) => (if d.as_os_str().is_empty() { Path::new(".") } else { d }, f.to_os_string()),
+        _ => { return Err(format!("invalid runtime config path {}", path)); }
+    };
+    let inotify = match Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC) {
+        Ok(i) => i,
+        Err(e) => { return Err(format!("can't init inotify: {}", e)); }
+    };
+    if let Err(e) = inotify.add_watch(dir, AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO) {
+        return Err(format!("can't watch {}: {}", dir.display(), e));
+    }
+    let (wake, wake_rx) = match UnixStream::pair() {
+        Ok(p) => p,
+        Err(e) => { return Err(format!("can't create runtime config watcher wake up socket: {}", e)); }
+    };
+
+    let path = path.to_string();
+    let handle = thread::Builder::new().name("cfg-watcher".to_string()).spawn(move || {
+        let debounce = Duration::from_millis(RUNTIME_CFG_DEBOUNCE as u64);
+        // when to read the file, pushed back on each write. we read once right away to pick up changes
+        // made before the watch was in place.
+        let mut read_at: Option<Instant> = Some(Instant::now());
+        loop {
+            let timeout = match read_at {
+                Some(t) => t.saturating_duration_since(Instant::now()).as_millis() as i32,
+                None => -1,
+            };
+            let mut fds = [PollFd::new(&inotify, PollFlags::POLLIN), PollFd::new(&wake_rx, PollFlags::POLLIN)];
+            match poll(&mut fds, timeout) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(e) => {
+                    log::error!("runtime config watch failed: {}", e);
+                    break;
+                }
+            }
+            // the other end is dropped on stop
+            if fds[1].any().unwrap_or(false) {
+                break;
+            }
+            if fds[0].any().unwrap_or(false) {
+                match inotify.read_events() {
+                    Ok(events) => {
+                        if events.iter().any(|e| e.name.as_deref() == Some(OsStr::new(&file_name))) {
+                            read_at = Some(Instant::now() + debounce);
+                        }
+                    }
+                    Err(Errno::EAGAIN) => {}
+                    Err(e) => { log::warn!("runtime config watch failed: {}", e); }
+                }
+            }
+
+            if read_at.is_some_and(|t| Instant::now() >= t) {
+                read_at = None;
+                let res = RuntimeCfg::load(&path).map_err(|e| e.to_string());
+                match res.and_then(|cfg| cfg.validate(state.max_thread_count.load(Relaxed)).map(|_| cfg)) {
+                    Ok(cfg) => {
+                        if tx.send(cfg).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => { log::warn!("runtime config update rejected: {}", e); }
+                }
+            }
+        }
+        log::info!("runtime config watcher exited.");
+    });
+    match handle {
+        Ok(handle) => Ok(RuntimeCfgWatcher { handle, wake }),
+        Err(e) => Err(format!("can't spawn runtime config watcher thread: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::PowState;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn runtime_cfg_watcher_test() {
+        let dir = std::env::temp_dir().join(format!("randomx_cfg_watcher_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("runtime_cfg.json");
+        let path_str = path.to_str().unwrap().to_string();
+        fs::write(&path, r#"{"deallocated_threads": 0, "operator_update": 1694962278}"#).unwrap();
+
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let state = Arc::new(PowState::new(dir.join("nonces.json").to_str().unwrap()));
+        state.max_thread_count.swap(4, Relaxed);
+        let watcher = spawn_runtime_cfg_watcher(&path_str, tx, Arc::clone(&state)).unwrap();
+        let wait = Duration::from_secs(5);
+
+        // the initial read
+        assert_eq!(rx.recv_timeout(wait).unwrap().deallocated_threads, 0);
+
+        // atomic replace
+        let tmp = dir.join("runtime_cfg.json.tmp");
+        fs::write(&tmp, r#"{"deallocated_threads": 2, "operator_update": 1694962279}"#).unwrap();
+        fs::rename(&tmp, &path).unwrap();
+        assert_eq!(rx.recv_timeout(wait).unwrap().deallocated_threads, 2);
+
+        // torn, out of range and unknown fields are rejected
+        fs::write(&path, r#"{"deallocated_thr"#).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(4 * RUNTIME_CFG_DEBOUNCE as u64)).is_err());
+        fs::write(&path, r#"{"deallocated_threads": 4, "operator_update": 1694962280}"#).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(4 * RUNTIME_CFG_DEBOUNCE as u64)).is_err());
+        fs::write(&path, r#"{"deallocated_threads": 1, "operator_update": 1694962280, "dealloc": 3}"#).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(4 * RUNTIME_CFG_DEBOUNCE as u64)).is_err());
+
+        // writes in quick succession only produce the last update
+        fs::write(&path, r#"{"deallocated_threads": 3, "operator_update": 1694962281}"#).unwrap();
+        fs::write(&path, r#"{"deallocated_threads": 1, "operator_update": 1694962282}"#).unwrap();
+        assert_eq!(rx.recv_timeout(wait).unwrap().deallocated_threads, 1);
+        assert!(rx.recv_timeout(Duration::from_millis(4 * RUNTIME_CFG_DEBOUNCE as u64)).is_err());
+
+        watcher.stop().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
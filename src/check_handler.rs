@@ -0,0 +1,299 @@
+use nix::unistd::{access, AccessFlags};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::cfg_handler::{self, RandomxCfg, RuntimeCfg, RXThreading};
+use crate::cli_handler::Paths;
+use crate::compute_units::{self, ChainRegistry};
+use crate::error::{Error, EXIT_CONFIG};
+use crate::key_handler;
+use crate::keyblock::KeyBlockSourceCfg;
+use crate::precompute::DATASET_MB;
+use crate::verifier;
+
+// `check-config` subcommand. a dry run of what run needs from the host: the setup and runtime configs,
+// the keypair, endpoint uris, cpus and memory for the compute units and the paths we write to. unlike run,
+// which stops at the first problem, every problem is reported at once. nothing is contacted or written.
+
+// randomx light mode cache, in MB
+pub const CACHE_MB: u64 = 256;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Host {
+    pub cpus: u32,
+    pub available_memory: Option<u64>,
+}
+
+impl Host {
+    pub fn current() -> Self {
+        Host {
+            cpus: std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1),
+            available_memory: available_memory(),
+        }
+    }
+}
+
+// MemAvailable in bytes, linux only
+fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64)
+}
+
+// datasets of the hashing threads, twice that while precomputing the next key block's, and the verifier's
+// light mode caches
+pub fn memory_requirement(cfg: &RandomxCfg, compute_units: u32) -> u64 {
+    let mut datasets = match cfg.thread_model {
+        RXThreading::SINGLE => compute_units as u64,
+        RXThreading::MULTI => 1,
+    };
+    if cfg.precompute.enabled {
+        datasets *= 2;
+    }
+    (datasets * DATASET_MB + verifier::cache_size(cfg.thread_model, compute_units) as u64 * CACHE_MB) << 20
+}
+
+fn check_uri(field: &str, uri: &str) -> Result<(), String> {
+    match reqwest::Url::parse(uri) {
+        Ok(u) if u.scheme() == "http" || u.scheme() == "https" => Ok(()),
+        Ok(u) => Err(format!("{} {} has scheme {} but json-rpc needs http or https.", field, uri, u.scheme())),
+        Err(e) => Err(format!("{} {:?} is not a valid uri: {}. e.g., https://api.node.glif.io/rpc/v1", field, uri, e)),
+    }
+}
+
+// missing dirs are created on run so the closest existing one needs to be writable
+fn check_writable(name: &str, path: &str, is_dir: bool) -> Result<(), String> {
+    let path = Path::new(path);
+    if path.exists() {
+        if path.is_dir() != is_dir {
+            let kind = if is_dir { "dir" } else { "file" };
+            return Err(format!("{} {} exists but is not a {}.", name, path.display(), kind));
+        }
+        return match access(path, AccessFlags::W_OK) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{} {} is not writable: {}. fix its permissions or point {} elsewhere.", name, path.display(), e, name)),
+        };
+    }
+    let existing = match path.ancestors().skip(1).find(|a| a.as_os_str().is_empty() || a.exists()) {
+        Some(a) if a.as_os_str().is_empty() => Path::new("."),
+        Some(a) => a,
+        None => Path::new("/"),
+    };
+    if !existing.is_dir() {
+        return Err(format!("{} {} can't be created, {} is not a dir.", name, path.display(), existing.display()));
+    }
+    match access(existing, AccessFlags::W_OK) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{} {} can't be created, {} is not writable: {}.", name, path.display(), existing.display(), e)),
+    }
+}
+
+// returns the config and its number of compute units if it could be parsed at all. env overrides and the
+// profile are applied the same way as on run.
+fn check_setup_cfg(path: &str, host: &Host, env: &[(String, String)], problems: &mut Vec<String>) -> Option<(RandomxCfg, Option<u32>)> {
+    let value: Value = match cfg_handler::layered_value(path, env) {
+        Ok(v) => v,
+        Err(e @ Error::Io { .. }) => {
+            problems.push(format!("{}. pass --setup-cfg or --data-dir.", e));
+            return None;
+        }
+        Err(e) => {
+            problems.push(format!("{}.", e));
+            return None;
+        }
+    };
+    if let Some(fields) = value.as_object() {
+        let known = cfg_handler::setup_cfg_fields();
+        for field in fields.keys().filter(|f| !known.contains(f)) {
+            problems.push(format!("unknown field {} is ignored. check its spelling, known fields are {}.", field, known.join(", ")));
+        }
+    }
+    let cfg: RandomxCfg = match serde_json::from_value(value) {
+        Ok(c) => c,
+        Err(e) => {
+            problems.push(format!("setup config {} doesn't match the expected fields: {}.", path, e));
+            return None;
+        }
+    };
+    problems.extend(cfg.problems());
+
+    if !cfg.keypair.is_empty() || cfg.keypair_path.is_some() {
+        if let Err(e) = key_handler::keypair_from_cfg(&cfg) {
+            problems.push(format!("{}.", e));
+        }
+    }
+
+    match cfg.key_block_source {
+        None => problems.extend(check_uri("key_blockchain_uri", &cfg.key_blockchain_uri).err()),
+        Some(KeyBlockSourceCfg::Eth { ref uri }) | Some(KeyBlockSourceCfg::Lotus { ref uri }) => {
+            problems.extend(check_uri("key_block_source uri", uri).err());
+        }
+        Some(KeyBlockSourceCfg::File { ref path }) => {
+            if !Path::new(path).is_file() {
+                problems.push(format!("key_block_source path {} is not a file.", path));
+            }
+        }
+        Some(_) => {}
+    }
+    for uri in &cfg.rpc.fallback_uris {
+        problems.extend(check_uri("rpc fallback_uris", uri).err());
+    }
+
+    let compute_units = match ChainRegistry::from_cfg(&cfg) {
+        Ok(Some(registry)) => {
+            problems.extend(check_uri("registry uri", &registry.uri).err());
+            None
+        }
+        Ok(None) => match compute_units::registry(&cfg, &cfg.peer_id) {
+            Ok(ids) => Some(ids.len() as u32),
+            Err(e) => {
+                problems.push(format!("{}.", e));
+                None
+            }
+        },
+        Err(e) => {
+            problems.push(format!("{}.", e));
+            None
+        }
+    };
+
+    let threads = cfg.num_cores * cfg.threads_per_core;
+    if threads > host.cpus {
+        problems.push(format!("num_cores * threads_per_core is {} but this host only has {} cpus. lower them to what the host provides.", threads, host.cpus));
+    }
+    // compute units from the on-chain registry are only known once running, assume one per thread
+    let units = compute_units.unwrap_or(threads);
+    if let Some(available) = host.available_memory {
+        let required = memory_requirement(&cfg, units);
+        if required > available {
+            problems.push(format!(
+                "{} compute units with the {} thread model{} need about {} but only {} are available. use fewer compute units, the multi thread model or disable precompute.",
+                units,
+                format!("{:?}", cfg.thread_model).to_lowercase(),
+                if cfg.precompute.enabled { " and precompute" } else { "" },
+                gib(required),
+                gib(available)
+            ));
+        }
+    }
+    Some((cfg, compute_units))
+}
+
+pub fn check(paths: &Paths, host: &Host, env: &[(String, String)]) -> Vec<String> {
+    let mut problems = vec![];
+    let setup = check_setup_cfg(&paths.setup_cfg, host, env, &mut problems);
+
+    match RuntimeCfg::load_with_env(&paths.runtime_cfg, env) {
+        Ok(runtime_cfg) => {
+            if let Some(units) = setup.as_ref().and_then(|(_, units)| *units) {
+                if let Err(e) = runtime_cfg.validate(units) {
+                    problems.push(format!("runtime config {}: {}. at least one compute unit needs to stay allocated.", paths.runtime_cfg, e));
+                }
+            }
+        }
+        Err(e) => problems.push(format!("{}. pass --runtime-cfg or --data-dir.", e)),
+    }
+
+    let mut writable = vec![
+        ("log", paths.log.as_str(), false),
+        ("pid", paths.pid.as_str(), false),
+        ("solution dir", paths.solution_dir.as_str(), true),
+        ("nonce state", paths.nonce_state.as_str(), false),
+    ];
+    if let Some(socket) = setup.as_ref().and_then(|(cfg, _)| cfg.control_socket.as_deref()) {
+        writable.push(("control_socket", socket, false));
+    }
+    for (name, path, is_dir) in writable {
+        problems.extend(check_writable(name, path, is_dir).err());
+    }
+    problems
+}
+
+pub fn run(paths: &Paths) -> i32 {
+    let env: Vec<(String, String)> = std::env::vars().collect();
+    let problems = check(paths, &Host::current(), &env);
+    if problems.is_empty() {
+        println!("{} and {} are ok.", paths.setup_cfg, paths.runtime_cfg);
+        return 0;
+    }
+    for problem in &problems {
+        println!("- {}", problem);
+    }
+    println!("{} problems found.", problems.len());
+    EXIT_CONFIG
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PEER_ID: &str = "12D3KooWRF2opN6PWhi13KfJKFpKQ5uoVe6TL8VuGnLRagjpGfmV";
+    const KEYPAIR_B58: &str = "2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3";
+
+    fn setup_cfg() -> Value {
+        serde_json::json!({
+            "num_cores": 2,
+            "threads_per_core": 2,
+            "peer_id": PEER_ID,
+            "keypair": KEYPAIR_B58,
+            "thread_model": "single",
+            "puzzle": "zeros",
+            "difficulty": 18,
+            "key_blockchain_uri": "https://api.node.glif.io/rpc/v1",
+        })
+    }
+
+    #[test]
+    fn check_config_test() {
+        let dir = std::env::temp_dir().join(format!("randomx_check_{}", std::process::id()));
+        fs::create_dir_all(dir.join("data")).unwrap();
+        let paths = Paths::from_data_dir(dir.to_str().unwrap());
+        fs::write(&paths.runtime_cfg, r#"{"deallocated_threads": 1, "operator_update": 1694962278}"#).unwrap();
+        let host = Host { cpus: 4, available_memory: Some(64 << 30) };
+
+        // every field is known and serialized
+        let cfg: RandomxCfg = serde_json::from_value(setup_cfg()).unwrap();
+        let serialized = serde_json::to_value(&cfg).unwrap();
+        let mut fields: Vec<String> = serialized.as_object().unwrap().keys().cloned().collect();
+        fields.sort();
+        let mut known = cfg_handler::setup_cfg_fields();
+        known.sort();
+        assert_eq!(fields, known);
+
+        fs::write(&paths.setup_cfg, setup_cfg().to_string()).unwrap();
+        assert_eq!(check(&paths, &host, &[]), Vec::<String>::new());
+        assert_eq!(check(&paths, &host, &[("RANDOMX_DIFFICULTY".to_string(), "0".to_string())]).len(), 1);
+
+        // all problems at once
+        let mut invalid = setup_cfg();
+        invalid["peer_ids"] = serde_json::json!(PEER_ID);
+        invalid["difficulty"] = serde_json::json!(0);
+        invalid["peer_id"] = serde_json::json!(fluence_keypair::KeyPair::generate_ed25519().get_peer_id().to_base58());
+        invalid["key_blockchain_uri"] = serde_json::json!("glif.io/rpc/v1");
+        invalid["num_cores"] = serde_json::json!(16);
+        invalid["control_socket"] = serde_json::json!(format!("{}/sock", paths.runtime_cfg));
+        fs::write(&paths.setup_cfg, invalid.to_string()).unwrap();
+        let problems = check(&paths, &Host { cpus: 4, available_memory: Some(8 << 30) }, &[]);
+        let expected = ["unknown field peer_ids", "invalid difficulty 0", "operator keypair", "key_blockchain_uri", "num_cores * threads_per_core is 32", "32 compute units", "control_socket"];
+        assert_eq!(problems.len(), expected.len(), "{:?}", problems);
+        for (problem, start) in problems.iter().zip(expected) {
+            assert!(problem.contains(start), "{} doesn't mention {}", problem, start);
+        }
+
+        // the runtime config is checked against the compute units
+        fs::write(&paths.setup_cfg, setup_cfg().to_string()).unwrap();
+        fs::write(&paths.runtime_cfg, r#"{"deallocated_threads": 4, "operator_update": 1694962278}"#).unwrap();
+        assert_eq!(check(&paths, &host, &[]).len(), 1);
+
+        fs::write(&paths.setup_cfg, "{").unwrap();
+        fs::remove_file(&paths.runtime_cfg).unwrap();
+        assert_eq!(check(&paths, &host, &[]).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
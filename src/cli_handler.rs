@@ -0,0 +1,287 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::Error;
+
+// command line. every path can be set with a flag or env var, flags win, and defaults to the usual layout
+// under --data-dir so several instances can run side by side.
+
+pub const USAGE: &str = "usage: randomx-wrapper [path options] [command] [command options]
+
+commands:
+  run [--socket <path>]                   start mining (default)
+  verify [--json] <file or dir>...        verify solution files
+  bench [--seconds N] [--threads N] [--light]
+                                          measure the hash rate
+  keygen [--out <path>]                   generate an ed25519 keypair
+  check-config                            check the configs, keypair, endpoints and host without mining
+  status [--socket <path>]                query a running instance
+  dealloc [--socket <path>] (--count N | --thread-ids <id,...>)
+                                          deallocate compute units of a running instance
+  help                                    print this message
+
+path options:                             env var
+  --data-dir <dir>                        RANDOMX_DATA_DIR       default .
+  --log-path <path>                       RANDOMX_LOG_PATH       default <data dir>/logs/log.txt
+  --pid-path <path>                       RANDOMX_PID_PATH       default <data dir>/pid.json
+  --setup-cfg <path>                      RANDOMX_SETUP_CFG      default <data dir>/data/randomx_cfg.toml or .json
+  --runtime-cfg <path>                    RANDOMX_RUNTIME_CFG    default <data dir>/data/runtime_cfg.toml or .json
+  --solution-dir <dir>                    RANDOMX_SOLUTION_DIR   default <data dir>/puzzle-solutions
+  --nonce-state <path>                    RANDOMX_NONCE_STATE    default <data dir>/data/nonce_state.json
+  --socket <path>                         RANDOMX_CONTROL_SOCKET default control_socket of the setup config";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Paths {
+    pub data_dir: String,
+    pub log: String,
+    pub pid: String,
+    pub setup_cfg: String,
+    pub runtime_cfg: String,
+    pub solution_dir: String,
+    pub nonce_state: String,
+}
+
+fn join(root: &str, rel: &str) -> String {
+    Path::new(root).join(rel).to_string_lossy().to_string()
+}
+
+// toml if there is one, json otherwise
+fn cfg_path(root: &str, name: &str) -> String {
+    let toml = join(root, &format!("data/{}.toml", name));
+    match Path::new(&toml).exists() {
+        true => toml,
+        false => join(root, &format!("data/{}.json", name)),
+    }
+}
+
+impl Paths {
+    pub fn from_data_dir(data_dir: &str) -> Self {
+        Paths {
+            data_dir: data_dir.to_string(),
+            log: join(data_dir, "logs/log.txt"),
+            pid: join(data_dir, "pid.json"),
+            setup_cfg: cfg_path(data_dir, "randomx_cfg"),
+            runtime_cfg: cfg_path(data_dir, "runtime_cfg"),
+            solution_dir: join(data_dir, "puzzle-solutions"),
+            nonce_state: join(data_dir, "data/nonce_state.json"),
+        }
+    }
+
+    // directories we write to, configs need to exist already
+    pub fn create_dirs(&self) -> Result<(), Error> {
+        let mut dirs = vec![self.solution_dir.as_str()];
+        for file in [&self.log, &self.pid, &self.nonce_state] {
+            if let Some(parent) = Path::new(file).parent().and_then(|p| p.to_str()) {
+                dirs.push(parent);
+            }
+        }
+        for dir in dirs.into_iter().filter(|d| !d.is_empty()) {
+            if let Err(e) = fs::create_dir_all(dir) {
+                return Err(Error::io(format!("can't create dir {}", dir), e));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Paths::from_data_dir(".")
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Run { socket: Option<String> },
+    Verify { json: bool, files: Vec<String> },
+    Bench { seconds: u64, threads: u32, light: bool },
+    Keygen { out: Option<String> },
+    CheckConfig,
+    Status { socket: Option<String> },
+    Dealloc { socket: Option<String>, count: Option<u32>, thread_ids: Option<Vec<String>> },
+    Help,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cli {
+    pub command: Command,
+    pub paths: Paths,
+}
+
+const PATH_FLAGS: [(&str, &str); 7] = [
+    ("--data-dir", "RANDOMX_DATA_DIR"),
+    ("--log-path", "RANDOMX_LOG_PATH"),
+    ("--pid-path", "RANDOMX_PID_PATH"),
+    ("--setup-cfg", "RANDOMX_SETUP_CFG"),
+    ("--runtime-cfg", "RANDOMX_RUNTIME_CFG"),
+    ("--solution-dir", "RANDOMX_SOLUTION_DIR"),
+    ("--nonce-state", "RANDOMX_NONCE_STATE"),
+];
+const VALUE_FLAGS: [&str; 6] = ["--seconds", "--threads", "--out", "--socket", "--count", "--thread-ids"];
+
+// flags with their values and positional args
+type SplitArgs = (Vec<(String, Option<String>)>, Vec<String>);
+
+// splits --flag=value and pulls the value of flags that take one
+fn split_flags(args: &[String]) -> Result<SplitArgs, String> {
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let (name, value) = match arg.split_once('=') {
+            Some((n, v)) => (n.to_string(), Some(v.to_string())),
+            None => (arg.clone(), None),
+        };
+        let takes_value = PATH_FLAGS.iter().any(|(f, _)| *f == name) || VALUE_FLAGS.contains(&name.as_str());
+        let value = match (takes_value, value) {
+            (true, Some(v)) => Some(v),
+            (true, None) => match iter.next() {
+                Some(v) => Some(v.clone()),
+                None => { return Err(format!("{} needs a value", name)); }
+            },
+            (false, Some(_)) => { return Err(format!("{} doesn't take a value", name)); }
+            (false, None) => None,
+        };
+        flags.push((name, value));
+    }
+    Ok((flags, positional))
+}
+
+fn parse_num<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("{} takes a number but got {}", name, value)),
+    }
+}
+
+pub fn parse(args: &[String], env: &dyn Fn(&str) -> Option<String>) -> Result<Cli, String> {
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        return Ok(Cli { command: Command::Help, paths: Paths::default() });
+    }
+    let (flags, positional) = split_flags(args)?;
+    let flag = |name: &str| flags.iter().rev().find(|(n, _)| n == name).and_then(|(_, v)| v.clone());
+    let has = |name: &str| flags.iter().any(|(n, _)| n == name);
+    let path = |name: &str, var: &str| flag(name).or_else(|| env(var));
+
+    let mut paths = Paths::from_data_dir(&path("--data-dir", "RANDOMX_DATA_DIR").unwrap_or(".".to_string()));
+    let overrides = [
+        (&mut paths.log, PATH_FLAGS[1]),
+        (&mut paths.pid, PATH_FLAGS[2]),
+        (&mut paths.setup_cfg, PATH_FLAGS[3]),
+        (&mut paths.runtime_cfg, PATH_FLAGS[4]),
+        (&mut paths.solution_dir, PATH_FLAGS[5]),
+        (&mut paths.nonce_state, PATH_FLAGS[6]),
+    ];
+    for (field, (name, var)) in overrides {
+        if let Some(p) = path(name, var) {
+            *field = p;
+        }
+    }
+
+    let command_name = positional.first().map(|c| c.as_str()).unwrap_or("run");
+    let operands = positional.iter().skip(1).cloned().collect::<Vec<String>>();
+    let allowed: &[&str] = match command_name {
+        "help" | "check-config" => &[],
+        "run" => &["--socket"],
+        "verify" => &["--json"],
+        "bench" => &["--seconds", "--threads", "--light"],
+        "keygen" => &["--out"],
+        "status" => &["--socket"],
+        "dealloc" => &["--socket", "--count", "--thread-ids"],
+        _ => { return Err(format!("unknown command {}", command_name)); }
+    };
+    if let Some((name, _)) = flags.iter().find(|(n, _)| !allowed.contains(&n.as_str()) && !PATH_FLAGS.iter().any(|(f, _)| f == n)) {
+        return Err(format!("unknown option {} for {}", name, command_name));
+    }
+    if command_name != "verify" && !operands.is_empty() {
+        return Err(format!("unexpected argument {}", operands[0]));
+    }
+
+    let socket = || path("--socket", "RANDOMX_CONTROL_SOCKET");
+    let command = match command_name {
+        "run" => Command::Run { socket: socket() },
+        "help" => Command::Help,
+        "verify" => {
+            if operands.is_empty() {
+                return Err("verify needs at least one solution file or dir".to_string());
+            }
+            Command::Verify { json: has("--json"), files: operands }
+        }
+        "bench" => Command::Bench {
+            seconds: match flag("--seconds") { Some(s) => parse_num("--seconds", &s)?, None => 10 },
+            threads: match flag("--threads") { Some(t) => parse_num("--threads", &t)?, None => 1 },
+            light: has("--light"),
+        },
+        "keygen" => Command::Keygen { out: flag("--out") },
+        "check-config" => Command::CheckConfig,
+        "status" => Command::Status { socket: socket() },
+        _ => {
+            let count = match flag("--count") { Some(c) => Some(parse_num("--count", &c)?), None => None };
+            let thread_ids = flag("--thread-ids").map(|ids| ids.split(',').filter(|i| !i.is_empty()).map(|i| i.to_string()).collect::<Vec<String>>());
+            if count.is_some() == thread_ids.is_some() {
+                return Err("dealloc takes either --count or --thread-ids".to_string());
+            }
+            Command::Dealloc { socket: socket(), count, thread_ids }
+        }
+    };
+    Ok(Cli { command, paths })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_test() {
+        let no_env = |_: &str| None;
+        let cli = parse(&[], &no_env).unwrap();
+        assert_eq!(cli.command, Command::Run { socket: None });
+        assert_eq!(cli.paths, Paths::default());
+        assert_eq!(cli.paths.setup_cfg, "./data/randomx_cfg.json");
+
+        let cli = parse(&args("--data-dir /var/lib/randomx run --log-path=/var/log/randomx.log"), &no_env).unwrap();
+        assert_eq!(cli.paths.setup_cfg, "/var/lib/randomx/data/randomx_cfg.json");
+        assert_eq!(cli.paths.solution_dir, "/var/lib/randomx/puzzle-solutions");
+        assert_eq!(cli.paths.log, "/var/log/randomx.log");
+
+        // flags win over env vars
+        let env = |var: &str| match var {
+            "RANDOMX_DATA_DIR" => Some("/srv/a".to_string()),
+            "RANDOMX_PID_PATH" => Some("/run/a.pid".to_string()),
+            "RANDOMX_CONTROL_SOCKET" => Some("/run/a.sock".to_string()),
+            _ => None,
+        };
+        let cli = parse(&args("status --pid-path /run/b.pid"), &env).unwrap();
+        assert_eq!(cli.paths.pid, "/run/b.pid");
+        assert_eq!(cli.paths.runtime_cfg, "/srv/a/data/runtime_cfg.json");
+        assert_eq!(cli.command, Command::Status { socket: Some("/run/a.sock".to_string()) });
+        // the daemon listens where the clients are pointed
+        assert_eq!(parse(&[], &env).unwrap().command, Command::Run { socket: Some("/run/a.sock".to_string()) });
+        assert_eq!(parse(&args("run --socket /run/b.sock"), &env).unwrap().command, Command::Run { socket: Some("/run/b.sock".to_string()) });
+
+        let cli = parse(&args("verify --json a.json dir"), &no_env).unwrap();
+        assert_eq!(cli.command, Command::Verify { json: true, files: args("a.json dir") });
+        let cli = parse(&args("bench --threads 4 --light"), &no_env).unwrap();
+        assert_eq!(cli.command, Command::Bench { seconds: 10, threads: 4, light: true });
+        let cli = parse(&args("dealloc --thread-ids aa,bb --socket s"), &no_env).unwrap();
+        assert_eq!(cli.command, Command::Dealloc { socket: Some("s".to_string()), count: None, thread_ids: Some(args("aa bb")) });
+
+        let cli = parse(&args("check-config --setup-cfg cfg.json"), &no_env).unwrap();
+        assert_eq!((cli.command, cli.paths.setup_cfg.as_str()), (Command::CheckConfig, "cfg.json"));
+
+        assert!(parse(&args("mine"), &no_env).is_err());
+        assert!(parse(&args("verify"), &no_env).is_err());
+        assert!(parse(&args("run --json"), &no_env).is_err());
+        assert!(parse(&args("bench --seconds ten"), &no_env).is_err());
+        assert!(parse(&args("dealloc --count 1 --thread-ids aa"), &no_env).is_err());
+        assert!(parse(&args("keygen --out"), &no_env).is_err());
+    }
+}
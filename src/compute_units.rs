@@ -0,0 +1,315 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::time::Duration;
+
+use crate::cfg_handler::RandomxCfg;
+use crate::hashers::keccak_hasher;
+use crate::keyblock;
+use crate::mocks;
+
+// compute unit ids, one randomx thread each. they come from the on-chain registry contract if configured,
+// otherwise from thread_ids in the setup config or the registry file at thread_ids_path, a json array of
+// ids. without any of these we fall back to ids mocked from the peer id, one per core and thread.
+
+pub const UNIT_ID_LEN: usize = 32;
+
+// 32 bytes hex, with or without 0x. returns the lowercase hex used for thread names.
+pub fn parse_unit_id(id: &str) -> Result<String, String> {
+    let trimmed = id.trim();
+    let hex_str = trimmed.strip_prefix("0x").unwrap_or(trimmed);
+    match hex::decode(hex_str) {
+        Ok(b) if b.len() == UNIT_ID_LEN => Ok(hex_str.to_lowercase()),
+        Ok(b) => Err(format!("compute unit id {} has {} bytes instead of {}", id, b.len(), UNIT_ID_LEN)),
+        Err(e) => Err(format!("compute unit id {} is not hex: {}", id, e)),
+    }
+}
+
+pub fn parse_unit_ids(ids: &[String]) -> Result<Vec<String>, String> {
+    let mut seen = HashSet::new();
+    let mut unit_ids = vec![];
+    for id in ids {
+        let unit_id = parse_unit_id(id)?;
+        if !seen.insert(unit_id.clone()) {
+            return Err(format!("duplicate compute unit id {}", unit_id));
+        }
+        unit_ids.push(unit_id);
+    }
+    Ok(unit_ids)
+}
+
+pub fn mocked_unit_ids(peer_id: &str, count: u32) -> Vec<String> {
+    (0..count).map(|i| mocks::ThreadId::new(peer_id, &i).to_hex()).collect()
+}
+
+fn registry_file(path: &str) -> Result<Vec<String>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => { return Err(format!("can't read compute unit registry {}: {}", path, e)); }
+    };
+    match serde_json::from_str(&content) {
+        Ok(ids) => Ok(ids),
+        Err(e) => Err(format!("can't parse compute unit registry {}: {}", path, e)),
+    }
+}
+
+// the pool is sized to the returned ids
+pub fn registry(cfg: &RandomxCfg, peer_id: &str) -> Result<Vec<String>, String> {
+    let ids = match cfg.thread_ids_path {
+        Some(ref path) => registry_file(path)?,
+        None => cfg.thread_ids.clone(),
+    };
+    if ids.is_empty() {
+        log::warn!("no compute unit ids configured, using mocked ids.");
+        return Ok(mocked_unit_ids(peer_id, cfg.num_cores * cfg.threads_per_core));
+    }
+    let unit_ids = parse_unit_ids(&ids)?;
+    if unit_ids.len() as u32 > cfg.num_cores * cfg.threads_per_core {
+        log::warn!("{} compute units oversubscribe {} cores with {} threads each.", unit_ids.len(), cfg.num_cores, cfg.threads_per_core);
+    }
+    Ok(unit_ids)
+}
+
+// registry contract function listing the compute units assigned to a peer id, i.e., the base58 decoded
+// multihash, as bytes32 ids
+pub const REGISTRY_FUNCTION: &str = "getComputeUnitIds(bytes)";
+
+fn default_refresh_secs() -> u64 {
+    600
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct RegistryCfg {
+    // 0x prefixed contract address
+    pub contract: String,
+    // json-rpc endpoint, defaults to key_blockchain_uri
+    #[serde(default)]
+    pub uri: Option<String>,
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u64,
+}
+
+fn abi_word(n: usize) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[24..].copy_from_slice(&(n as u64).to_be_bytes());
+    word
+}
+
+fn abi_usize(word: &[u8]) -> Result<usize, String> {
+    if word.len() != 32 || word[..24].iter().any(|b| *b != 0) {
+        return Err("abi integer out of range".to_string());
+    }
+    match usize::try_from(u64::from_be_bytes(word[24..].try_into().unwrap())) {
+        Ok(n) => Ok(n),
+        Err(_) => Err("abi integer out of range".to_string()),
+    }
+}
+
+// calldata for REGISTRY_FUNCTION: selector, offset and length of the bytes arg and the zero padded bytes
+pub fn encode_registry_call(peer_id: &str) -> Result<String, String> {
+    let peer_id_bytes = match bs58::decode(peer_id).into_vec() {
+        Ok(b) => b,
+        Err(e) => { return Err(format!("invalid peer id {}: {}", peer_id, e)); }
+    };
+    let mut data = keccak_hasher(REGISTRY_FUNCTION)[..4].to_vec();
+    data.extend_from_slice(&abi_word(32));
+    data.extend_from_slice(&abi_word(peer_id_bytes.len()));
+    data.extend_from_slice(&peer_id_bytes);
+    data.resize(4 + 64 + peer_id_bytes.len().div_ceil(32) * 32, 0);
+    Ok(format!("0x{}", hex::encode(data)))
+}
+
+// bytes32[] return data: offset, length and the ids
+pub fn decode_unit_ids(result: &str) -> Result<Vec<String>, String> {
+    let data = match hex::decode(result.trim_start_matches("0x")) {
+        Ok(d) => d,
+        Err(e) => { return Err(format!("registry returned invalid hex: {}", e)); }
+    };
+    if data.len() < 64 {
+        return Err(format!("registry returned {} bytes, too short for an array", data.len()));
+    }
+    // offset and length come from the node, anything past the data is an error rather than an overflow
+    let offset = abi_usize(&data[..32])?;
+    let start = match offset.checked_add(32) {
+        Some(s) => s,
+        None => { return Err(format!("registry array offset {} out of range", offset)); }
+    };
+    let len = match data.get(offset..start) {
+        Some(word) => abi_usize(word)?,
+        None => { return Err(format!("registry array offset {} out of range", offset)); }
+    };
+    let end = match len.checked_mul(32).and_then(|n| n.checked_add(start)) {
+        Some(e) => e,
+        None => { return Err(format!("registry array of {} ids is truncated", len)); }
+    };
+    match data.get(start..end) {
+        Some(ids) => parse_unit_ids(&ids.chunks(32).map(hex::encode).collect::<Vec<String>>()),
+        None => Err(format!("registry array of {} ids is truncated", len)),
+    }
+}
+
+// lists our compute units via eth_call, over the same json-rpc transport as the key block sources
+pub struct ChainRegistry {
+    pub uri: String,
+    pub contract: String,
+    pub refresh: Duration,
+    timeout: Duration,
+}
+
+impl ChainRegistry {
+    pub fn from_cfg(cfg: &RandomxCfg) -> Result<Option<Self>, String> {
+        let registry = match cfg.registry {
+            Some(ref r) => r,
+            None => { return Ok(None); }
+        };
+        let address = registry.contract.strip_prefix("0x").unwrap_or("");
+        if address.len() != 40 || hex::decode(address).is_err() {
+            return Err(format!("invalid registry contract address {}", registry.contract));
+        }
+        Ok(Some(ChainRegistry {
+            uri: registry.uri.clone().unwrap_or(cfg.key_blockchain_uri.clone()),
+            contract: registry.contract.clone(),
+            refresh: Duration::from_secs(registry.refresh_secs),
+            timeout: cfg.rpc.timeout(),
+        }))
+    }
+
+    pub fn name(&self) -> String {
+        format!("registry {} at {}", self.contract, self.uri)
+    }
+
+    pub fn unit_ids(&self, peer_id: &str) -> Result<Vec<String>, String> {
+        let call = serde_json::json!([{"to": self.contract, "data": encode_registry_call(peer_id)?}, "latest"]);
+        let result = keyblock::json_rpc_call(&self.uri, "eth_call", call, self.timeout)?;
+        match result.as_str() {
+            Some(r) => decode_unit_ids(r),
+            None => Err(format!("eth_call to {} returned no data", self.contract)),
+        }
+    }
+}
+
+// returns the added and the removed ids
+pub fn diff(current: &[String], updated: &[String]) -> (Vec<String>, Vec<String>) {
+    let added = updated.iter().filter(|id| !current.contains(id)).cloned().collect();
+    let removed = current.iter().filter(|id| !updated.contains(id)).cloned().collect();
+    (added, removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const ID: &str = "b877077c72f1852ab6edcf1ab688444ea047b2115419e7f03fe66f9947667cb9";
+
+    #[test]
+    fn unit_id_test() {
+        assert_eq!(parse_unit_id(ID), Ok(ID.to_string()));
+        assert_eq!(parse_unit_id(&format!("0x{}", ID.to_uppercase())), Ok(ID.to_string()));
+        assert!(parse_unit_id("").is_err());
+        assert!(parse_unit_id(&ID[2..]).is_err());
+        assert!(parse_unit_id(&ID.replace('b', "z")).is_err());
+
+        let other = mocked_unit_ids("peer", 1).remove(0);
+        assert_eq!(parse_unit_ids(&[ID.to_string(), other.clone()]), Ok(vec![ID.to_string(), other]));
+        assert_eq!(parse_unit_ids(&[ID.to_string(), format!("0x{}", ID)]), Err(format!("duplicate compute unit id {}", ID)));
+    }
+
+    fn encode_result(ids: &[String]) -> String {
+        let mut data = abi_word(32).to_vec();
+        data.extend_from_slice(&abi_word(ids.len()));
+        for id in ids {
+            data.extend_from_slice(&hex::decode(id).unwrap());
+        }
+        format!("0x{}", hex::encode(data))
+    }
+
+    // answers eth_call with the current ids, one request per connection
+    fn mock_rpc_server(contract: &'static str, ids: Arc<Mutex<Vec<String>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                        content_length = v.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let req: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(req["method"], "eth_call");
+                assert_eq!(req["params"][0]["to"], contract);
+
+                let res = serde_json::json!({"jsonrpc": "2.0", "id": req["id"], "result": encode_result(&ids.lock().unwrap())}).to_string();
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", res.len(), res);
+            }
+        });
+        uri
+    }
+
+    #[test]
+    fn registry_abi_test() {
+        let data = encode_registry_call("12D3KooWRF2opN6PWhi13KfJKFpKQ5uoVe6TL8VuGnLRagjpGfmV").unwrap();
+        // selector, offset, length and the 38 byte multihash padded to 64 bytes
+        assert_eq!(data.len(), 2 + 2 * (4 + 32 + 32 + 64));
+        assert_eq!(&data[2..10], &hex::encode(&keccak_hasher(REGISTRY_FUNCTION)[..4]));
+        assert_eq!(&data[74..138], hex::encode(abi_word(38)));
+        assert!(encode_registry_call("not base58 0OIl").is_err());
+
+        let ids = mocked_unit_ids("peer", 2);
+        assert_eq!(decode_unit_ids(&encode_result(&ids)), Ok(ids.clone()));
+        assert_eq!(decode_unit_ids(&encode_result(&[])), Ok(vec![]));
+        let truncated = encode_result(&ids);
+        assert!(decode_unit_ids(&truncated[..truncated.len() - 64]).is_err());
+        assert!(decode_unit_ids("0x00").is_err());
+        // offsets and lengths that would overflow
+        let max_word = format!("{}{}", "00".repeat(24), "ff".repeat(8));
+        assert!(decode_unit_ids(&format!("0x{}{}", max_word, "00".repeat(32))).is_err());
+        assert!(decode_unit_ids(&format!("0x{}{}", hex::encode(abi_word(32)), max_word)).is_err());
+    }
+
+    #[test]
+    fn chain_registry_test() {
+        const CONTRACT: &str = "0x5fbdb2315678afecb367f032d93f642f64180aa3";
+        let ids = mocked_unit_ids("peer", 3);
+        let assigned = Arc::new(Mutex::new(ids[..2].to_vec()));
+        let uri = mock_rpc_server(CONTRACT, Arc::clone(&assigned));
+
+        let mut cfg: RandomxCfg = serde_json::from_value(serde_json::json!({
+            "num_cores": 2,
+            "threads_per_core": 1,
+            "peer_id": "12D3KooWRF2opN6PWhi13KfJKFpKQ5uoVe6TL8VuGnLRagjpGfmV",
+            "keypair": "",
+            "thread_model": "single",
+            "puzzle": "zeros",
+            "difficulty": 18,
+            "key_blockchain_uri": uri,
+            "registry": {"contract": CONTRACT},
+        }))
+        .unwrap();
+        let registry = ChainRegistry::from_cfg(&cfg).unwrap().unwrap();
+        assert_eq!(registry.refresh, Duration::from_secs(600));
+        let peer_id = cfg.peer_id.clone();
+        assert_eq!(registry.unit_ids(&peer_id), Ok(ids[..2].to_vec()));
+
+        // units added and removed on chain
+        *assigned.lock().unwrap() = vec![ids[1].clone(), ids[2].clone()];
+        let updated = registry.unit_ids(&peer_id).unwrap();
+        assert_eq!(diff(&ids[..2], &updated), (vec![ids[2].clone()], vec![ids[0].clone()]));
+
+        cfg.registry = Some(RegistryCfg { contract: "0x1234".to_string(), uri: None, refresh_secs: 60 });
+        assert!(ChainRegistry::from_cfg(&cfg).is_err());
+    }
+}
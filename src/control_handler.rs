@@ -0,0 +1,265 @@
+use crossbeam::channel::{bounded, Sender};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::state::SharedState;
+
+// local control api for Nox and other orchestration. one json object per line in, one json object per line out:
+//   {"cmd": "status"}
+//   {"cmd": "dealloc", "count": 2}
+//   {"cmd": "dealloc", "thread_ids": ["<hex thread id>", ...]}
+//   {"cmd": "realloc"} or {"cmd": "realloc", "count": 1} or {"cmd": "realloc", "thread_ids": [...]}
+//   {"cmd": "set-difficulty", "difficulty": 18}
+//   {"cmd": "shutdown"}
+// commands are executed by the main loop and only answered once they took effect. dealloc and realloc
+// report the affected compute unit ids in changed_threads.
+
+const CONTROL_REPLY_TIMEOUT: u64 = 10 * 60 * 1_000; // in millis, realloc waits for dataset init
+const CONTROL_IDLE_TIMEOUT: u64 = 60 * 1_000; // in millis, idle clients are disconnected
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum ControlCmd {
+    Status,
+    Dealloc {
+        #[serde(default)]
+        count: Option<u32>,
+        #[serde(default)]
+        thread_ids: Option<Vec<String>>,
+    },
+    Realloc {
+        #[serde(default)]
+        count: Option<u32>,
+        #[serde(default)]
+        thread_ids: Option<Vec<String>>,
+    },
+    SetDifficulty { difficulty: u32 },
+    Shutdown,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Default)]
+pub struct ControlResponse {
+    pub ok: bool,
+    pub error: Option<String>,
+    pub changed_threads: Vec<String>,
+    pub alloc_threads: Vec<String>,
+    pub dealloc_threads: Vec<String>,
+    pub difficulty: u32,
+    pub key_block: u64,
+}
+
+pub struct ControlRequest {
+    pub cmd: ControlCmd,
+    pub reply: Sender<ControlResponse>,
+}
+
+fn error_response(e: String) -> ControlResponse {
+    ControlResponse { ok: false, error: Some(e), ..ControlResponse::default() }
+}
+
+fn handle_connection(stream: UnixStream, requests: &Sender<ControlRequest>) {
+    let _ = stream.set_nonblocking(false);
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(CONTROL_IDLE_TIMEOUT))) {
+        log::warn!("control connection failed: {}", e);
+        return;
+    }
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => { log::warn!("control connection failed: {}", e); return; }
+    };
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlCmd>(&line) {
+            Ok(cmd) => {
+                log::info!("control command {:?}", cmd);
+                let (reply_tx, reply_rx) = bounded(1);
+                match requests.send(ControlRequest { cmd, reply: reply_tx }) {
+                    Ok(_) => match reply_rx.recv_timeout(Duration::from_millis(CONTROL_REPLY_TIMEOUT)) {
+                        Ok(r) => r,
+                        Err(_) => error_response("main loop did not answer in time".to_string()),
+                    },
+                    Err(_) => error_response("main loop is gone".to_string()),
+                }
+            }
+            Err(e) => error_response(format!("invalid command: {}", e)),
+        };
+        let mut out = serde_json::to_string(&response).unwrap();
+        out.push('\n');
+        if writer.write_all(out.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+// client side for the status and dealloc subcommands
+pub fn send_command(path: &str, cmd: &ControlCmd) -> Result<ControlResponse, String> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(s) => s,
+        Err(e) => { return Err(format!("can't connect to control socket {}: {}", path, e)); }
+    };
+    let mut line = serde_json::to_string(cmd).unwrap();
+    line.push('\n');
+    if let Err(e) = stream.write_all(line.as_bytes()) {
+        return Err(format!("can't send command: {}", e));
+    }
+    let mut response = String::new();
+    if let Err(e) = BufReader::new(stream).read_line(&mut response) {
+        return Err(format!("can't read response: {}", e));
+    }
+    match serde_json::from_str::<ControlResponse>(&response) {
+        Ok(r) => Ok(r),
+        Err(e) => Err(format!("invalid response {}: {}", response.trim(), e)),
+    }
+}
+
+// runs a control command against a running instance and prints the response, returns the exit code
+pub fn run_client(path: &str, cmd: &ControlCmd) -> i32 {
+    match send_command(path, cmd) {
+        Ok(r) => {
+            println!("{}", serde_json::to_string_pretty(&r).unwrap());
+            match r.ok {
+                true => 0,
+                false => 1,
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// binds the socket, owner read/write only, and serves each connection on its own thread until app exit.
+// commands are still executed one at a time by the main loop.
+pub fn spawn_control_listener(path: &str, requests: Sender<ControlRequest>, state: SharedState) -> Result<thread::JoinHandle<()>, String> {
+    // a stale socket from a previous run is replaced, anything else at the path is left alone
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+        if !meta.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket. remove it or point control_socket elsewhere.", path));
+        }
+        if let Err(e) = fs::remove_file(path) {
+            return Err(format!("can't remove stale control socket {}: {}", path, e));
+        }
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(l) => l,
+        Err(e) => { return Err(format!("can't bind control socket {}: {}", path, e)); }
+    };
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+            return Err(format!("can't set control socket permissions: {}", e));
+        }
+    }
+    if let Err(e) = listener.set_nonblocking(true) {
+        return Err(format!("can't set control socket to non-blocking: {}", e));
+    }
+
+    let path = path.to_string();
+    let handle = thread::Builder::new().name("control".to_string()).spawn(move || {
+        while !state.exiting() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let requests = requests.clone();
+                    let spawned = thread::Builder::new().name("control-conn".to_string()).spawn(move || handle_connection(stream, &requests));
+                    if let Err(e) = spawned {
+                        log::warn!("can't spawn control connection thread: {}", e);
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                Err(e) => {
+                    log::warn!("control socket accept failed: {}", e);
+                    thread::sleep(Duration::from_millis(1_000));
+                }
+            }
+        }
+        let _ = fs::remove_file(&path);
+        log::info!("control socket closed.");
+    });
+    match handle {
+        Ok(h) => Ok(h),
+        Err(e) => Err(format!("can't spawn control thread: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::PowState;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::Arc;
+
+    #[test]
+    fn control_cmd_test() {
+        assert_eq!(serde_json::from_str::<ControlCmd>(r#"{"cmd": "status"}"#).unwrap(), ControlCmd::Status);
+        assert_eq!(
+            serde_json::from_str::<ControlCmd>(r#"{"cmd": "dealloc", "count": 2}"#).unwrap(),
+            ControlCmd::Dealloc { count: Some(2), thread_ids: None }
+        );
+        assert_eq!(
+            serde_json::from_str::<ControlCmd>(r#"{"cmd": "set-difficulty", "difficulty": 18}"#).unwrap(),
+            ControlCmd::SetDifficulty { difficulty: 18 }
+        );
+        assert_eq!(serde_json::from_str::<ControlCmd>(r#"{"cmd": "realloc"}"#).unwrap(), ControlCmd::Realloc { count: None, thread_ids: None });
+        assert!(serde_json::from_str::<ControlCmd>(r#"{"cmd": "reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn control_socket_test() {
+        let path = std::env::temp_dir().join(format!("randomx_ctl_test_{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let (tx, rx) = crossbeam::channel::unbounded::<ControlRequest>();
+        let state = Arc::new(PowState::new(&format!("{}.nonces", path)));
+        let handle = spawn_control_listener(&path, tx, Arc::clone(&state)).unwrap();
+
+        // stand-in for the main loop
+        thread::spawn(move || {
+            for req in rx.iter() {
+                let ok = req.cmd == ControlCmd::Status;
+                let _ = req.reply.send(ControlResponse { ok, alloc_threads: vec!["a".to_string()], ..ControlResponse::default() });
+            }
+        });
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"{\"cmd\": \"status\"}\nnot json\n").unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        let status: ControlResponse = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert!(status.ok);
+        assert_eq!(status.alloc_threads, vec!["a".to_string()]);
+        let invalid: ControlResponse = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert!(!invalid.ok);
+        assert!(invalid.error.unwrap().starts_with("invalid command"));
+
+        // a client that doesn't send anything doesn't hold up the others
+        let _stalled = UnixStream::connect(&path).unwrap();
+        let status = send_command(&path, &ControlCmd::Status).unwrap();
+        assert_eq!(status.alloc_threads, vec!["a".to_string()]);
+        assert!(!send_command(&path, &ControlCmd::Shutdown).unwrap().ok);
+
+        // the listener removes its socket on exit
+        state.app_exit.swap(true, Relaxed);
+        handle.join().unwrap();
+        assert!(fs::metadata(&path).is_err());
+
+        // only sockets are replaced
+        fs::write(&path, "not a socket").unwrap();
+        let (tx, _rx) = crossbeam::channel::unbounded::<ControlRequest>();
+        assert!(spawn_control_listener(&path, tx, state).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,784 @@
+use chrono::Utc;
+use crossbeam::channel::{bounded, Sender};
+use fluence_keypair::KeyPair;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cfg_handler::{RandomxCfg, RXThreading};
+use crate::compute_units::{self, ChainRegistry};
+use crate::control_handler::{ControlCmd, ControlRequest, ControlResponse};
+use crate::error::Error;
+use crate::keyblock::{self, KeyBlockSource};
+use crate::metrics;
+use crate::pow::RandomxPool;
+use crate::precompute;
+use crate::puzzle::PuzzleSolution;
+use crate::verifier::{self, SolutionSink, VerifierStats};
+use crate::state::{PowState, SharedState};
+use crate::{paths, DEALLOC_TIMEOUT, KEYBLOCK_CHECK_INTERVAL, KEYPAIR, PEERID, SHUTDOWN_TIMEOUT};
+
+// public api to embed the PoW in a node process. the controller owns the randomx pool, tracks the key block
+// and hands verified solutions to the sink and callbacks. call tick() periodically, e.g., every few seconds,
+// to pick up key block changes.
+//
+//   let mut controller = Controller::new(cfg, keypair, 0)?;
+//   controller.on_solution(|s| submit(s));
+//   controller.start()?;
+//   loop { controller.tick(); ... }
+//   controller.stop(Duration::from_secs(30));
+
+// nonce state files of the controllers in this process. two controllers leasing from the same file would
+// overwrite each other's leases and hash the same nonces after a restart.
+static NONCE_STATES: LazyLock<Mutex<HashMap<String, Weak<PowState>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub type SolutionCallback = Box<dyn Fn(&PuzzleSolution) + Send>;
+pub type KeyBlockCallback = Box<dyn Fn(u64) + Send>;
+// allocated and deallocated compute unit ids after a change
+pub type AllocationCallback = Box<dyn Fn(&[String], &[String]) + Send>;
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ShutdownSummary {
+    pub stopped_threads: Vec<String>,
+    // threads that didn't acknowledge the stop before the deadline
+    pub unresponsive_threads: Vec<String>,
+    // false if the verifier didn't drain the solution queue before the deadline
+    pub flushed: bool,
+    pub verified_solutions: u64,
+    pub rejected_solutions: u64,
+    pub elapsed: Duration,
+}
+
+impl ShutdownSummary {
+    pub fn clean(&self) -> bool {
+        self.unresponsive_threads.is_empty() && self.flushed
+    }
+}
+
+pub struct Controller {
+    cfg: RandomxCfg,
+    state: SharedState,
+    compute_units: Vec<String>,
+    chain_registry: Option<Arc<ChainRegistry>>,
+    deallocated_threads: u32,
+    last_registry_check: i64,
+    key_block_source: Arc<dyn KeyBlockSource>,
+    // periodic key block fetch, including its retries and backoff
+    key_block_fetch: Option<thread::JoinHandle<Result<(u64, bool), Error>>>,
+    registry_fetch: Option<thread::JoinHandle<Result<Vec<String>, String>>>,
+    persist_solutions: bool,
+    // shared with the verifier's sink, so the controller keeps them should start fail
+    solution_callbacks: Arc<Mutex<Vec<SolutionCallback>>>,
+    key_block_callbacks: Vec<KeyBlockCallback>,
+    allocation_callbacks: Vec<AllocationCallback>,
+    solution_tx: Option<Sender<PuzzleSolution>>,
+    pool: Option<RandomxPool>,
+    verifier: Option<thread::JoinHandle<VerifierStats>>,
+    metrics: Option<thread::JoinHandle<()>>,
+    last_key_block_check: i64,
+}
+
+impl Controller {
+    // deallocated_threads is the number of compute units not to start with, e.g., from the runtime config.
+    // with an on-chain registry the compute units are only known, and deallocated_threads checked, on start.
+    // nonce leases are kept in the nonce state file of paths(), see with_nonce_state for more controllers.
+    pub fn new(cfg: RandomxCfg, keypair: KeyPair, deallocated_threads: u32) -> Result<Self, Error> {
+        Controller::with_nonce_state(cfg, keypair, deallocated_threads, &paths().nonce_state)
+    }
+
+    // each controller in a process needs its own nonce state file
+    pub fn with_nonce_state(cfg: RandomxCfg, keypair: KeyPair, deallocated_threads: u32, nonce_state_path: &str) -> Result<Self, Error> {
+        // the key block schedule divides by offset and delay, amongst others
+        cfg.validate().map_err(|e| Error::config(&paths().setup_cfg, e))?;
+        let peer_id = keypair.get_peer_id().to_base58();
+        if peer_id != cfg.peer_id {
+            return Err(Error::Signing(format!("keypair belongs to peer id {} but config specifies peer id {}", peer_id, cfg.peer_id)));
+        }
+        if KEYPAIR.get().is_some() && *PEERID.as_str() != peer_id {
+            return Err(Error::Signing("a controller for another peer id already exists in this process".to_string()));
+        }
+        let _ = KEYPAIR.set(Arc::new(keypair));
+
+        let chain_registry = ChainRegistry::from_cfg(&cfg).map_err(|e| Error::config(&paths().setup_cfg, e))?.map(Arc::new);
+        let compute_units = match chain_registry {
+            Some(_) => vec![],
+            None => compute_units::registry(&cfg, &peer_id).map_err(|e| Error::config(&paths().setup_cfg, e))?,
+        };
+        let max_threads = compute_units.len() as u32;
+
+        let mut nonce_states = NONCE_STATES.lock().unwrap();
+        nonce_states.retain(|_, s| s.strong_count() > 0);
+        if nonce_states.contains_key(nonce_state_path) {
+            return Err(Error::config(nonce_state_path, "nonce state is in use by another controller in this process"));
+        }
+        let state = Arc::new(PowState::from_cfg(&cfg, max_threads, 0, nonce_state_path));
+        nonce_states.insert(nonce_state_path.to_string(), Arc::downgrade(&state));
+        drop(nonce_states);
+
+        let key_block_source = Arc::from(keyblock::source_from_cfg(&cfg, Arc::clone(&state.metrics)));
+        let mut controller = Controller {
+            cfg,
+            state,
+            compute_units,
+            chain_registry,
+            deallocated_threads: 0,
+            last_registry_check: 0,
+            key_block_source,
+            key_block_fetch: None,
+            registry_fetch: None,
+            persist_solutions: true,
+            solution_callbacks: Arc::default(),
+            key_block_callbacks: vec![],
+            allocation_callbacks: vec![],
+            solution_tx: None,
+            pool: None,
+            verifier: None,
+            metrics: None,
+            last_key_block_check: 0,
+        };
+        controller.set_start_dealloc(deallocated_threads)?;
+        Ok(controller)
+    }
+
+    // the number of compute units not to start with, checked like deallocated_threads of new. once started,
+    // use set_dealloc_target.
+    pub fn set_start_dealloc(&mut self, deallocated_threads: u32) -> Result<(), Error> {
+        if self.pool.is_some() {
+            return Err(Error::Usage("controller is already started, use set_dealloc_target".to_string()));
+        }
+        let max_threads = self.state.max_thread_count.load(Relaxed);
+        if self.chain_registry.is_none() && deallocated_threads >= max_threads {
+            return Err(Error::config(&paths().runtime_cfg, format!("can't deallocate {} of {} compute units", deallocated_threads, max_threads)));
+        }
+        self.deallocated_threads = deallocated_threads;
+        self.state.alloc_thread_count.swap(max_threads.saturating_sub(deallocated_threads), Relaxed);
+        Ok(())
+    }
+
+    pub fn peer_id(&self) -> String {
+        PEERID.to_string()
+    }
+
+    // all compute unit ids, allocated or not
+    pub fn compute_units(&self) -> &[String] {
+        &self.compute_units
+    }
+
+    // shared with the randomx threads and workers, e.g., for the control listener to exit with the controller
+    pub fn state(&self) -> SharedState {
+        Arc::clone(&self.state)
+    }
+
+    // replaces the source built from the config, e.g., with the node's own chain client
+    pub fn set_key_block_source(&mut self, source: Box<dyn KeyBlockSource>) {
+        self.key_block_source = Arc::from(source);
+    }
+
+    // verified solutions are written to the solution dir unless disabled. set before start.
+    pub fn persist_solutions(&mut self, persist: bool) {
+        self.persist_solutions = persist;
+    }
+
+    // called from the verifier thread with each verified solution. register before start.
+    pub fn on_solution<F: Fn(&PuzzleSolution) + Send + 'static>(&mut self, callback: F) {
+        self.solution_callbacks.lock().unwrap().push(Box::new(callback));
+    }
+
+    pub fn on_key_block<F: Fn(u64) + Send + 'static>(&mut self, callback: F) {
+        self.key_block_callbacks.push(Box::new(callback));
+    }
+
+    pub fn on_allocation<F: Fn(&[String], &[String]) + Send + 'static>(&mut self, callback: F) {
+        self.allocation_callbacks.push(Box::new(callback));
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    pub fn key_block(&self) -> u64 {
+        self.state.current_keyblock.load(Relaxed)
+    }
+
+    // fetches the key block and starts the verifier and the randomx pool. blocks until the allocated compute
+    // units finished dataset initialization. fails with Error::Rpc if the key block or the on-chain compute
+    // units aren't available so callers can retry.
+    pub fn start(&mut self) -> Result<(), Error> {
+        if self.pool.is_some() {
+            return Err(Error::Usage("controller is already started".to_string()));
+        }
+        keyblock::keyblock_handler(&*self.key_block_source, &self.state)?;
+        self.last_key_block_check = Utc::now().timestamp_millis();
+        log::info!("got initial key block {}.", self.key_block());
+        self.state.metrics.set_key_block(self.key_block());
+        self.fetch_compute_units()?;
+
+        // randomx channel to communicate puzzle solutions to the verifier. solutions queue up until the
+        // verifier is up so the pool goes first, there's nothing to undo if it fails.
+        // only the allocated compute units are started, the others are deallocated from the start
+        let dealloc = (self.state.max_thread_count.load(Relaxed) - self.state.alloc_thread_count.load(Relaxed)) as usize;
+        if dealloc >= self.compute_units.len() {
+            return Err(Error::config(&paths().runtime_cfg, format!("can't deallocate {} of {} compute units", dealloc, self.compute_units.len())));
+        }
+        let (allocated, deallocated) = self.compute_units.split_at(self.compute_units.len() - dealloc);
+        let (tx, rx) = bounded(verifier::SOLUTION_QUEUE_SIZE);
+        let mut pool = RandomxPool::new(self.state(), tx.clone());
+        pool.start(allocated.iter().map(|s| s.as_str()).collect())?;
+        pool.add_deallocated(deallocated);
+
+        let mut file_sink = match self.persist_solutions {
+            true => Some(verifier::file_sink(None)),
+            false => None,
+        };
+        let callbacks = Arc::clone(&self.solution_callbacks);
+        let sink: SolutionSink = Box::new(move |solution: &PuzzleSolution| {
+            if let Some(ref mut f) = file_sink {
+                f(solution);
+            }
+            for callback in callbacks.lock().unwrap().iter() {
+                callback(solution);
+            }
+        });
+        match verifier::spawn_verifier(rx, sink, self.state()) {
+            Ok(h) => self.verifier = Some(h),
+            Err(e) => {
+                // randomx threads only stop on app exit, we reset it so start can be retried
+                self.state.app_exit.swap(true, Relaxed);
+                let (_, unresponsive) = pool.join_until(Instant::now() + Duration::from_millis(SHUTDOWN_TIMEOUT as u64));
+                if !unresponsive.is_empty() {
+                    log::error!("randomx threads {:?} didn't stop after the verifier failed to start.", unresponsive);
+                }
+                self.state.app_exit.swap(false, Relaxed);
+                return Err(e);
+            }
+        }
+        log::info!("waiting for randomx disk to be initiated for each instance. This takes a while.");
+        pool.wait_up(self.state.alloc_thread_count.load(Relaxed));
+        log::info!("{} randomx disks are initiated.", pool.up_count());
+        self.pool = Some(pool);
+        self.solution_tx = Some(tx);
+
+        if let Some(port) = self.cfg.metrics_port {
+            match metrics::spawn_metrics_server(port, self.state()) {
+                Ok(h) => {
+                    log::info!("metrics are up at http://127.0.0.1:{}/metrics.", port);
+                    self.metrics = Some(h);
+                }
+                Err(e) => { log::error!("metrics not available: {}", e); }
+            }
+        }
+        self.notify_allocation();
+        Ok(())
+    }
+
+    // initial compute units from the on-chain registry. an invalid dealloc is ignored rather than failing
+    // as it's only known to be invalid now.
+    fn fetch_compute_units(&mut self) -> Result<(), Error> {
+        let registry = match self.chain_registry {
+            Some(ref r) => r,
+            None => { return Ok(()); }
+        };
+        let unit_ids = match registry.unit_ids(&PEERID) {
+            Ok(ids) if ids.is_empty() => { return Err(Error::rpc(registry.name(), format!("no compute units assigned to peer id {}", *PEERID))); }
+            Ok(ids) => ids,
+            Err(e) => { return Err(Error::rpc(registry.name(), e)); }
+        };
+        self.last_registry_check = Utc::now().timestamp_millis();
+        let max_threads = unit_ids.len() as u32;
+        let dealloc = match self.deallocated_threads < max_threads {
+            true => self.deallocated_threads,
+            false => {
+                log::error!("invalid thread de-allocation: {} of {} compute units. dealloc request ignored.", self.deallocated_threads, max_threads);
+                0
+            }
+        };
+        self.state.max_thread_count.swap(max_threads, Relaxed);
+        self.state.alloc_thread_count.swap(max_threads - dealloc, Relaxed);
+        log::info!("got {} compute units from {}.", max_threads, registry.name());
+        self.compute_units = unit_ids;
+        Ok(())
+    }
+
+    // fetches the on-chain compute units and applies them, see apply_compute_units. blocks on the registry,
+    // tick() fetches on its own thread instead.
+    pub fn refresh_compute_units(&mut self) -> Result<(Vec<String>, Vec<String>), String> {
+        let unit_ids = match self.chain_registry {
+            Some(ref r) => r.unit_ids(&PEERID)?,
+            None => { return Err("no on-chain registry configured".to_string()); }
+        };
+        self.apply_compute_units(unit_ids)
+    }
+
+    // starts compute units newly assigned on chain and stops the ones no longer assigned. returns the added
+    // and removed compute unit ids. should a step fail, compute_units still has what was actually changed
+    // so the next diff starts from there.
+    fn apply_compute_units(&mut self, unit_ids: Vec<String>) -> Result<(Vec<String>, Vec<String>), String> {
+        // like on start, an empty assignment is more likely a registry hiccup than all units being gone, so
+        // we keep the pool
+        if unit_ids.is_empty() {
+            return Err(format!("no compute units assigned to peer id {}", *PEERID));
+        }
+        let (added, removed) = compute_units::diff(&self.compute_units, &unit_ids);
+        if added.is_empty() && removed.is_empty() {
+            return Ok((added, removed));
+        }
+        let pool = self.pool_mut()?;
+        let res = pool.remove_units(&removed, Duration::from_millis(DEALLOC_TIMEOUT as u64)).and_then(|_| pool.add_units(&added));
+        let in_pool = [pool.alloc(), pool.dealloced()].concat();
+        self.compute_units = self.compute_units.iter().chain(added.iter()).filter(|u| in_pool.contains(u)).cloned().collect();
+        self.state.max_thread_count.swap(self.compute_units.len() as u32, Relaxed);
+        self.notify_allocation();
+        res?;
+        log::info!("compute units changed on chain. added {:?} and removed {:?}.", added, removed);
+        Ok((added, removed))
+    }
+
+    // periodic housekeeping: key block and compute unit registry updates, precomputing the next key block's
+    // datasets and metrics
+    pub fn tick(&mut self) {
+        if self.pool.is_none() {
+            return;
+        }
+        self.check_key_block();
+        if self.key_block_fetch.is_none() && Utc::now().timestamp_millis() - self.last_key_block_check > (KEYBLOCK_CHECK_INTERVAL as i64) {
+            self.spawn_key_block_fetch();
+            self.last_key_block_check = Utc::now().timestamp_millis();
+        }
+        self.check_registry();
+        if let Some(refresh) = self.chain_registry.as_ref().map(|r| r.refresh.as_millis() as i64) {
+            if self.registry_fetch.is_none() && Utc::now().timestamp_millis() - self.last_registry_check > refresh {
+                self.spawn_registry_fetch();
+                self.last_registry_check = Utc::now().timestamp_millis();
+            }
+        }
+        self.precompute();
+        let (alloc, dealloc) = (self.alloc(), self.dealloced());
+        self.state.metrics.set_allocation(alloc.len() as u32, dealloc.len() as u32);
+    }
+
+    // fetches run on their own thread so retries and backoff against a flaky chain don't stall the caller
+    fn spawn_key_block_fetch(&mut self) {
+        let (source, state) = (Arc::clone(&self.key_block_source), self.state());
+        match thread::Builder::new().name("keyblock".to_string()).spawn(move || keyblock::keyblock_handler(&*source, &state)) {
+            Ok(h) => self.key_block_fetch = Some(h),
+            Err(e) => log::error!("can't spawn key block thread: {}", e),
+        }
+    }
+
+    fn spawn_registry_fetch(&mut self) {
+        let registry = match self.chain_registry {
+            Some(ref r) => Arc::clone(r),
+            None => { return; }
+        };
+        match thread::Builder::new().name("registry".to_string()).spawn(move || registry.unit_ids(&PEERID)) {
+            Ok(h) => self.registry_fetch = Some(h),
+            Err(e) => log::error!("can't spawn registry thread: {}", e),
+        }
+    }
+
+    // applies the result of a finished registry fetch
+    fn check_registry(&mut self) {
+        match self.registry_fetch {
+            Some(ref h) if h.is_finished() => {}
+            _ => { return; }
+        }
+        let res = match self.registry_fetch.take().map(|h| h.join()) {
+            Some(Ok(res)) => res,
+            _ => {
+                log::error!("registry thread panicked.");
+                return;
+            }
+        };
+        // an unreachable registry doesn't change our assignment so we keep on mining
+        if let Err(e) = res.and_then(|unit_ids| self.apply_compute_units(unit_ids)) {
+            log::warn!("compute unit registry not updated: {}", e);
+        }
+    }
+
+    // picks up the result of a finished key block fetch
+    fn check_key_block(&mut self) {
+        match self.key_block_fetch {
+            Some(ref h) if h.is_finished() => {}
+            _ => { return; }
+        }
+        let res = match self.key_block_fetch.take().map(|h| h.join()) {
+            Some(Ok(res)) => res,
+            _ => {
+                log::error!("key block thread panicked.");
+                return;
+            }
+        };
+        // an unreachable chain doesn't invalidate the current key block so we keep on mining with it
+        let (key_block, updated_kb) = match res {
+            Ok(kb) => kb,
+            Err(e) => {
+                log::warn!("{}. continue mining on current key block {}.", e, self.key_block());
+                return;
+            }
+        };
+        if !updated_kb {
+            return;
+        }
+        self.state.metrics.set_key_block(key_block);
+        // we got a new keyblock and need to tear down the randomx instances and initate with new disks
+        if self.state.hot_swap.load(Relaxed) {
+            log::info!("got a new key block {} and randomx threads hot swap to precomputed datasets.", key_block);
+        } else if let Some(ref mut pool) = self.pool {
+            log::info!("got a new key block {} and need to restart randomx threads.", key_block);
+            pool.restart();
+        }
+        for callback in &self.key_block_callbacks {
+            callback(key_block);
+        }
+    }
+
+    // prepare the next key block's datasets once we get close to it
+    fn precompute(&self) {
+        if !self.cfg.precompute.enabled {
+            return;
+        }
+        let schedule = &self.state.key_block_schedule;
+        let next_key_block = keyblock::next_keyblock(&self.key_block(), schedule);
+        let close_to_next = self.state.current_block_height.load(Relaxed) >= precompute::precompute_from(next_key_block, &self.cfg.precompute, schedule);
+        let prepared = &self.state.precompute;
+        if close_to_next && prepared.last_key_block() != next_key_block && !prepared.is_running() {
+            let alloc = self.alloc();
+            let contexts = crate::pow::signed_contexts(&next_key_block, &PEERID, &alloc, &self.cfg.thread_model);
+            let in_use = match self.cfg.thread_model {
+                RXThreading::SINGLE => alloc.len() as u64,
+                RXThreading::MULTI => 1,
+            };
+            precompute::spawn_precompute(self.state(), next_key_block, contexts, &self.cfg.precompute, in_use);
+        }
+    }
+
+    pub fn alloc(&self) -> Vec<String> {
+        self.pool.as_ref().map(|p| p.alloc()).unwrap_or_default()
+    }
+
+    pub fn dealloced(&self) -> Vec<String> {
+        self.pool.as_ref().map(|p| p.dealloced()).unwrap_or_default()
+    }
+
+    fn pool_mut(&mut self) -> Result<&mut RandomxPool, String> {
+        match self.pool {
+            Some(ref mut p) => Ok(p),
+            None => Err("controller is not started".to_string()),
+        }
+    }
+
+    fn notify_allocation(&self) {
+        let (alloc, dealloc) = (self.alloc(), self.dealloced());
+        self.state.metrics.set_allocation(alloc.len() as u32, dealloc.len() as u32);
+        for callback in &self.allocation_callbacks {
+            callback(&alloc, &dealloc);
+        }
+    }
+
+    // wraps pool changes to notify on actual changes, returns the changed compute unit ids
+    fn changed(&self, res: Result<Vec<String>, String>) -> Result<Vec<String>, String> {
+        if let Ok(ref changed) = res {
+            if !changed.is_empty() {
+                self.notify_allocation();
+            }
+        }
+        res
+    }
+
+    pub fn dealloc(&mut self, n: u32) -> Result<Vec<String>, String> {
+        let res = self.pool_mut()?.dealloc(n, Duration::from_millis(DEALLOC_TIMEOUT as u64));
+        self.changed(res)
+    }
+
+    pub fn dealloc_named(&mut self, thread_ids: &[String]) -> Result<Vec<String>, String> {
+        let res = self.pool_mut()?.dealloc_named(thread_ids, Duration::from_millis(DEALLOC_TIMEOUT as u64));
+        self.changed(res)
+    }
+
+    pub fn realloc(&mut self, n: u32) -> Result<Vec<String>, String> {
+        let res = self.pool_mut()?.realloc(n);
+        self.changed(res)
+    }
+
+    pub fn realloc_named(&mut self, thread_ids: &[String]) -> Result<Vec<String>, String> {
+        let res = self.pool_mut()?.realloc_named(thread_ids);
+        self.changed(res)
+    }
+
+    // deallocates or reallocates compute units until target units are deallocated
+    pub fn set_dealloc_target(&mut self, target: u32) -> Result<Vec<String>, String> {
+        let max_threads = self.state.max_thread_count.load(Relaxed);
+        let res = self.pool_mut()?.set_dealloc_target(target, max_threads, Duration::from_millis(DEALLOC_TIMEOUT as u64));
+        self.changed(res)
+    }
+
+    pub fn set_difficulty(&self, difficulty: u32) -> Result<(), String> {
+        let puzzle_type = *self.state.puzzle_type.read().unwrap();
+        if !puzzle_type.valid_difficulty(&difficulty) {
+            return Err(format!("invalid difficulty {} for puzzle {:?}", difficulty, puzzle_type));
+        }
+        self.state.puzzle_difficulty.swap(difficulty, Relaxed);
+        log::info!("puzzle difficulty set to {}.", difficulty);
+        Ok(())
+    }
+
+    // applies what can change at runtime from a reloaded setup config: difficulty, the key block source
+    // and the log level. datasets are kept. returns the applied settings, everything else needs a restart.
+    pub fn reload(&mut self, cfg: RandomxCfg) -> Result<Vec<String>, String> {
+        cfg.validate()?;
+        let old = &self.cfg;
+        let needs_restart: Vec<&str> = [
+            ("peer_id", old.peer_id != cfg.peer_id),
+            ("keypair", old.keypair != cfg.keypair || old.keypair_path != cfg.keypair_path),
+            ("num_cores", old.num_cores != cfg.num_cores),
+            ("threads_per_core", old.threads_per_core != cfg.threads_per_core),
+            ("thread_ids", old.thread_ids != cfg.thread_ids || old.thread_ids_path != cfg.thread_ids_path),
+            ("registry", old.registry != cfg.registry),
+            ("thread_model", old.thread_model != cfg.thread_model),
+            ("puzzle", old.puzzle != cfg.puzzle),
+            ("control_socket", old.control_socket != cfg.control_socket),
+            ("metrics_port", old.metrics_port != cfg.metrics_port),
+            ("precompute", old.precompute != cfg.precompute),
+            ("key_block_schedule", old.key_block_schedule != cfg.key_block_schedule),
+        ]
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect();
+        if !needs_restart.is_empty() {
+            log::warn!("reload ignores changes to {:?}, they need a restart.", needs_restart);
+        }
+
+        let mut applied = vec![];
+        if old.difficulty != cfg.difficulty {
+            self.set_difficulty(cfg.difficulty)?;
+            self.cfg.difficulty = cfg.difficulty;
+            applied.push("difficulty".to_string());
+        }
+        if self.cfg.log_level() != cfg.log_level() {
+            log::set_max_level(cfg.log_level());
+            self.cfg.log_level = cfg.log_level.clone();
+            applied.push("log level".to_string());
+        }
+        if self.cfg.key_blockchain_uri != cfg.key_blockchain_uri || self.cfg.key_block_source != cfg.key_block_source || self.cfg.rpc != cfg.rpc {
+            self.cfg.key_blockchain_uri = cfg.key_blockchain_uri;
+            self.cfg.key_block_source = cfg.key_block_source;
+            self.cfg.rpc = cfg.rpc;
+            self.key_block_source = Arc::from(keyblock::source_from_cfg(&self.cfg, Arc::clone(&self.state.metrics)));
+            applied.push("key block source".to_string());
+        }
+        log::info!("reloaded setup config, applied {:?}.", applied);
+        Ok(applied)
+    }
+
+    // executes a control command and returns the resulting allocation. shutdown only acknowledges, it's up
+    // to the caller to stop the controller.
+    pub fn execute(&mut self, cmd: &ControlCmd) -> ControlResponse {
+        let res = match cmd {
+            ControlCmd::Status | ControlCmd::Shutdown => Ok(vec![]),
+            ControlCmd::Dealloc { thread_ids: Some(ids), .. } => self.dealloc_named(ids),
+            ControlCmd::Dealloc { count: Some(n), .. } => self.dealloc(*n),
+            ControlCmd::Dealloc { .. } => Err("dealloc needs either count or thread_ids".to_string()),
+            ControlCmd::Realloc { thread_ids: Some(ids), .. } => self.realloc_named(ids),
+            ControlCmd::Realloc { count, .. } => {
+                let n = count.unwrap_or(self.dealloced().len() as u32);
+                self.realloc(n)
+            }
+            ControlCmd::SetDifficulty { difficulty } => self.set_difficulty(*difficulty).map(|_| vec![]),
+        };
+        if let Err(ref e) = res {
+            log::warn!("control command failed: {}", e);
+        }
+
+        let (changed_threads, error) = match res {
+            Ok(changed) => (changed, None),
+            Err(e) => (vec![], Some(e)),
+        };
+        ControlResponse {
+            ok: error.is_none(),
+            error,
+            changed_threads,
+            alloc_threads: self.alloc(),
+            dealloc_threads: self.dealloced(),
+            difficulty: self.state.puzzle_difficulty.load(Relaxed),
+            key_block: self.key_block(),
+        }
+    }
+
+    // executes a control socket request and replies. returns true on shutdown.
+    pub fn handle_control(&mut self, req: ControlRequest) -> bool {
+        let response = self.execute(&req.cmd);
+        let _ = req.reply.send(response);
+        req.cmd == ControlCmd::Shutdown
+    }
+
+    // signals all threads to exit and waits up to timeout for them to acknowledge. solutions still queued
+    // are verified and persisted before the verifier exits. whatever doesn't make the deadline is left
+    // running detached and reported in the summary, it's up to the caller to exit the process.
+    pub fn stop(mut self, timeout: Duration) -> ShutdownSummary {
+        let started = Instant::now();
+        let deadline = started + timeout;
+        self.state.app_exit.swap(true, Relaxed);
+        self.solution_tx = None;
+
+        let mut summary = ShutdownSummary { flushed: true, ..ShutdownSummary::default() };
+        if let Some(pool) = self.pool.take() {
+            let (stopped, unresponsive) = pool.join_until(deadline);
+            summary.stopped_threads = stopped;
+            summary.unresponsive_threads = unresponsive;
+        }
+        // hashing threads are done so nothing is added to the queue anymore
+        if let Some(h) = self.verifier.take() {
+            while !h.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(50));
+            }
+            match h.is_finished() {
+                true => match h.join() {
+                    Ok(stats) => {
+                        summary.verified_solutions = stats.verified;
+                        summary.rejected_solutions = stats.rejected;
+                    }
+                    Err(_) => {
+                        log::error!("verifier panicked");
+                        summary.flushed = false;
+                    }
+                },
+                false => summary.flushed = false,
+            }
+        }
+        if let Some(h) = self.metrics.take() {
+            let _ = h.join();
+        }
+        // in-flight key block and registry fetches are left to time out on their own, their results aren't needed anymore
+        self.key_block_fetch = None;
+        self.registry_fetch = None;
+        summary.elapsed = started.elapsed();
+
+        log::info!(
+            "controller stopped in {} millis: {} threads stopped, {} unresponsive {:?}, {} solutions verified, {} rejected, queue {}.",
+            summary.elapsed.as_millis(),
+            summary.stopped_threads.len(),
+            summary.unresponsive_threads.len(),
+            summary.unresponsive_threads,
+            summary.verified_solutions,
+            summary.rejected_solutions,
+            match summary.flushed { true => "flushed", false => "not flushed" },
+        );
+        summary
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEYPAIR_B58: &str = "2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3";
+    const PEER_ID: &str = "12D3KooWRF2opN6PWhi13KfJKFpKQ5uoVe6TL8VuGnLRagjpGfmV";
+
+    // every controller gets its own nonce state
+    fn new_controller(cfg: RandomxCfg, keypair: KeyPair, deallocated_threads: u32) -> Result<Controller, Error> {
+        static N: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!("randomx_ctl_nonces_{}_{}", std::process::id(), N.fetch_add(1, Relaxed)));
+        Controller::with_nonce_state(cfg, keypair, deallocated_threads, path.to_str().unwrap())
+    }
+
+    fn cfg(peer_id: &str) -> RandomxCfg {
+        serde_json::from_value(serde_json::json!({
+            "num_cores": 2,
+            "threads_per_core": 2,
+            "peer_id": peer_id,
+            "keypair": KEYPAIR_B58,
+            "thread_model": "single",
+            "puzzle": "zeros",
+            "difficulty": 18,
+            "key_blockchain_uri": "",
+            "key_block_source": {"type": "static", "height": 4_100},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn controller_setup_test() {
+        let keypair = || crate::key_handler::decode_keypair(KEYPAIR_B58).unwrap();
+        let other_peer = KeyPair::generate_ed25519().get_peer_id().to_base58();
+        assert!(new_controller(cfg(&other_peer), keypair(), 0).is_err());
+        assert!(new_controller(cfg(PEER_ID), keypair(), 4).is_err());
+        let mut invalid_schedule = cfg(PEER_ID);
+        invalid_schedule.key_block_schedule.offset = 0;
+        assert!(new_controller(invalid_schedule, keypair(), 0).is_err());
+
+        let mut controller = new_controller(cfg(PEER_ID), keypair(), 1).unwrap();
+        assert_eq!(controller.peer_id(), PEER_ID);
+        assert!(!controller.is_started());
+        assert!(controller.dealloc(1).is_err());
+        assert!(controller.set_start_dealloc(4).is_err());
+        controller.set_start_dealloc(2).unwrap();
+        assert_eq!(controller.state.alloc_thread_count.load(Relaxed), 2);
+        assert!(!controller.execute(&ControlCmd::Realloc { count: Some(1), thread_ids: None }).ok);
+        assert!(controller.set_difficulty(0).is_err());
+        assert_eq!(controller.compute_units(), compute_units::mocked_unit_ids(PEER_ID, 4).as_slice());
+
+        // only runtime settings are applied on reload
+        let mut reloaded = cfg(PEER_ID);
+        reloaded.difficulty = 20;
+        reloaded.num_cores = 8;
+        reloaded.key_block_source = Some(keyblock::KeyBlockSourceCfg::Static { height: 6_200 });
+        assert_eq!(controller.reload(reloaded).unwrap(), vec!["difficulty".to_string(), "key block source".to_string()]);
+        assert_eq!(controller.execute(&ControlCmd::Status).difficulty, 20);
+        assert_eq!(controller.cfg.num_cores, 2);
+        let mut invalid = cfg(PEER_ID);
+        invalid.log_level = Some("loud".to_string());
+        assert!(controller.reload(invalid).is_err());
+    }
+
+    #[test]
+    fn compute_unit_registry_test() {
+        let keypair = || crate::key_handler::decode_keypair(KEYPAIR_B58).unwrap();
+        let ids: Vec<String> = compute_units::mocked_unit_ids("registry", 3);
+
+        // the pool is sized to the registry rather than cores and threads
+        let mut registry_cfg = cfg(PEER_ID);
+        registry_cfg.thread_ids = ids.iter().map(|id| format!("0x{}", id)).collect();
+        assert!(new_controller(registry_cfg, keypair(), 3).is_err());
+        let mut registry_cfg = cfg(PEER_ID);
+        registry_cfg.thread_ids = ids.clone();
+        let controller = new_controller(registry_cfg, keypair(), 2).unwrap();
+        assert_eq!(controller.compute_units(), ids.as_slice());
+
+        let mut duplicate_cfg = cfg(PEER_ID);
+        duplicate_cfg.thread_ids = vec![ids[0].clone(), ids[1].clone(), ids[0].to_uppercase()];
+        let duplicate = format!("duplicate compute unit id {}", ids[0]);
+        assert!(matches!(new_controller(duplicate_cfg, keypair(), 0), Err(Error::Config { reason, .. }) if reason == duplicate));
+
+        // a registry file takes precedence
+        let path = std::env::temp_dir().join(format!("randomx_registry_{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_string(&ids[..2]).unwrap()).unwrap();
+        let mut file_cfg = cfg(PEER_ID);
+        file_cfg.thread_ids = ids.clone();
+        file_cfg.thread_ids_path = Some(path.to_str().unwrap().to_string());
+        assert_eq!(new_controller(file_cfg, keypair(), 0).unwrap().compute_units(), &ids[..2]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn separate_state_test() {
+        let keypair = || crate::key_handler::decode_keypair(KEYPAIR_B58).unwrap();
+        let path = std::env::temp_dir().join(format!("randomx_ctl_shared_nonces_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let a = Controller::with_nonce_state(cfg(PEER_ID), keypair(), 0, path).unwrap();
+        let shared = Controller::with_nonce_state(cfg(PEER_ID), keypair(), 0, path);
+        assert!(matches!(shared, Err(Error::Config { path: p, .. }) if p == path));
+
+        // nor are metrics
+        let b = new_controller(cfg(PEER_ID), keypair(), 0).unwrap();
+        a.state.metrics.set_key_block(4096);
+        assert!(b.state.metrics.render().contains("randomx_current_key_block 0\n"));
+        assert!(!Arc::ptr_eq(&a.state.metrics, &b.state.metrics));
+
+        // the nonce state is free again once its controller is gone
+        drop(a);
+        assert!(Controller::with_nonce_state(cfg(PEER_ID), keypair(), 0, path).is_ok());
+    }
+}
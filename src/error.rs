@@ -0,0 +1,99 @@
+use thiserror::Error;
+
+// errors that end the process, or a controller's start, by category. each category maps to its own exit
+// code and a hint on what to do about it. recoverable errors, e.g., a rejected control command, stay strings.
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid config {path}: {reason}")]
+    Config { path: String, reason: String },
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("rpc to {endpoint} failed: {reason}")]
+    Rpc { endpoint: String, reason: String },
+    #[error("operator keypair: {0}")]
+    Signing(String),
+    #[error("randomx: {0}")]
+    Randomx(String),
+    #[error("{0} channel closed")]
+    Channel(String),
+    // the controller api called out of order, e.g., start on a started controller
+    #[error("{0}")]
+    Usage(String),
+}
+
+// sysexits.h codes so supervisors can tell a broken config from an unreachable chain
+pub const EXIT_CONFIG: i32 = 78;
+pub const EXIT_IO: i32 = 74;
+pub const EXIT_RPC: i32 = 69;
+pub const EXIT_SIGNING: i32 = 77;
+pub const EXIT_RANDOMX: i32 = 70;
+pub const EXIT_CHANNEL: i32 = 71;
+pub const EXIT_USAGE: i32 = 64;
+
+impl Error {
+    pub fn config(path: &str, reason: impl Into<String>) -> Self {
+        Error::Config { path: path.to_string(), reason: reason.into() }
+    }
+
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        Error::Io { context: context.into(), source }
+    }
+
+    pub fn rpc(endpoint: impl Into<String>, reason: impl Into<String>) -> Self {
+        Error::Rpc { endpoint: endpoint.into(), reason: reason.into() }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config { .. } => EXIT_CONFIG,
+            Error::Io { .. } => EXIT_IO,
+            Error::Rpc { .. } => EXIT_RPC,
+            Error::Signing(_) => EXIT_SIGNING,
+            Error::Randomx(_) => EXIT_RANDOMX,
+            Error::Channel(_) => EXIT_CHANNEL,
+            Error::Usage(_) => EXIT_USAGE,
+        }
+    }
+
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Error::Config { .. } => "fix the config and restart",
+            Error::Io { .. } => "check the path exists and is writable by the miner",
+            Error::Rpc { .. } => "check the endpoint is reachable, see key_blockchain_uri, rpc and registry",
+            Error::Signing(_) => "check keypair or keypair_path matches peer_id, see keygen",
+            Error::Randomx(_) => "check free memory, each compute unit needs about 2 GB",
+            Error::Channel(_) => "a worker thread exited unexpectedly, see the log for its last entries",
+            Error::Usage(_) => "check the order of controller calls, e.g., start only once",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exit_code_test() {
+        let errors = [
+            Error::config("cfg.json", "invalid difficulty"),
+            Error::io("can't create dir logs", std::io::Error::from(std::io::ErrorKind::PermissionDenied)),
+            Error::rpc("eth:http://127.0.0.1:8545", "connection refused"),
+            Error::Signing("no keypair".to_string()),
+            Error::Randomx("can't spawn thread".to_string()),
+            Error::Channel("signal".to_string()),
+            Error::Usage("controller is already started".to_string()),
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(|e| e.exit_code()).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0) && !codes.contains(&1) && !codes.contains(&2));
+        assert_eq!(errors[0].to_string(), "invalid config cfg.json: invalid difficulty");
+        assert_eq!(errors[1].to_string(), "can't create dir logs: permission denied");
+    }
+}
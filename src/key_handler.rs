@@ -0,0 +1,178 @@
+use bs58;
+use fluence_keypair::{KeyFormat, KeyPair, PublicKey};
+use libp2p_identity::PeerId;
+use std::fs;
+use std::str::FromStr;
+
+use crate::cfg_handler::RandomxCfg;
+use crate::error::Error;
+
+// operator keypair management. the keypair is either the base58 encoded ed25519 keypair in the
+// setup config or, preferably, read from a separate key file only readable by the operator.
+
+#[cfg(unix)]
+fn check_permissions(path: &str) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let meta = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(e) => { return Err(format!("can't stat key file {}: {}", path, e)); }
+    };
+    let mode = meta.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(format!("key file {} has mode {:o} but must not be accessible by group or others. chmod 600 it.", path, mode));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &str) -> Result<(), String> {
+    Ok(())
+}
+
+pub fn decode_keypair(encoded: &str) -> Result<KeyPair, String> {
+    let bytes = match bs58::decode(encoded.trim()).into_vec() {
+        Ok(b) => b,
+        Err(e) => { return Err(format!("keypair is not valid base58: {}", e)); }
+    };
+    match KeyPair::from_vec(bytes, KeyFormat::Ed25519) {
+        Ok(kp) => Ok(kp),
+        Err(e) => Err(format!("keypair is not a valid ed25519 keypair: {}", e)),
+    }
+}
+
+pub fn encode_keypair(kp: &KeyPair) -> String {
+    bs58::encode(kp.to_vec()).into_string()
+}
+
+// creates the key file owner read/write only and refuses to overwrite an existing one
+#[cfg(unix)]
+pub fn write_keypair_file(path: &str, kp: &KeyPair) -> Result<(), String> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = match fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path) {
+        Ok(f) => f,
+        Err(e) => { return Err(format!("can't create key file {}: {}", path, e)); }
+    };
+    match file.write_all(encode_keypair(kp).as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("can't write key file {}: {}", path, e)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn write_keypair_file(path: &str, kp: &KeyPair) -> Result<(), String> {
+    match fs::File::create_new(path).and_then(|mut f| std::io::Write::write_all(&mut f, encode_keypair(kp).as_bytes())) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("can't write key file {}: {}", path, e)),
+    }
+}
+
+// keygen subcommand. prints the peer id and, without an out file, the keypair for the setup config.
+pub fn keygen(out: Option<&str>) -> i32 {
+    let kp = KeyPair::generate_ed25519();
+    let peer_id = kp.get_peer_id().to_base58();
+    match out {
+        Some(path) => match write_keypair_file(path, &kp) {
+            Ok(_) => {
+                println!("peer_id: {}", peer_id);
+                println!("keypair_path: {}", path);
+                0
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        },
+        None => {
+            println!("peer_id: {}", peer_id);
+            println!("keypair: {}", encode_keypair(&kp));
+            0
+        }
+    }
+}
+
+pub fn read_keypair_file(path: &str) -> Result<KeyPair, String> {
+    check_permissions(path)?;
+    match fs::read_to_string(path) {
+        Ok(s) => decode_keypair(&s),
+        Err(e) => Err(format!("can't read key file {}: {}", path, e)),
+    }
+}
+
+pub fn keypair_from_cfg(cfg: &RandomxCfg) -> Result<KeyPair, Error> {
+    let kp = match cfg.keypair_path {
+        Some(ref path) => read_keypair_file(path),
+        None => decode_keypair(&cfg.keypair),
+    };
+    let kp = kp.map_err(Error::Signing)?;
+
+    let peer_id = kp.get_peer_id().to_base58();
+    if peer_id != cfg.peer_id {
+        return Err(Error::Signing(format!("keypair belongs to peer id {} but config specifies peer id {}", peer_id, cfg.peer_id)));
+    }
+    Ok(kp)
+}
+
+// ed25519 peer ids inline the public key so verifiers only need the peer id
+pub fn public_key_from_peer_id(peer_id: &str) -> Result<PublicKey, String> {
+    let peer_id = match PeerId::from_str(peer_id) {
+        Ok(p) => p,
+        Err(e) => { return Err(format!("invalid peer id {}: {}", peer_id, e)); }
+    };
+    match PublicKey::try_from(peer_id) {
+        Ok(pk) => Ok(pk),
+        Err(e) => Err(format!("can't extract public key from peer id: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEYPAIR: &str = "2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3";
+    const PEER_ID: &str = "12D3KooWRF2opN6PWhi13KfJKFpKQ5uoVe6TL8VuGnLRagjpGfmV";
+
+    #[test]
+    fn decode_keypair_test() {
+        let kp = decode_keypair(KEYPAIR).unwrap();
+        assert_eq!(kp.get_peer_id().to_base58(), PEER_ID);
+        assert!(decode_keypair("not-base58-0OIl").is_err());
+        assert!(decode_keypair("2m2afA9bGZ").is_err());
+    }
+
+    #[test]
+    fn public_key_from_peer_id_test() {
+        let kp = decode_keypair(KEYPAIR).unwrap();
+        assert_eq!(public_key_from_peer_id(PEER_ID).unwrap(), kp.public());
+        assert!(public_key_from_peer_id("12D3KooW").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_file_permissions_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("randomx_key_test_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, KEYPAIR).unwrap();
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(read_keypair_file(path).is_err());
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+        let kp = read_keypair_file(path).unwrap();
+        assert_eq!(kp.get_peer_id().to_base58(), PEER_ID);
+
+        // keygen output reads back and is never overwritten
+        assert!(write_keypair_file(path, &kp).is_err());
+        fs::remove_file(path).unwrap();
+        let generated = KeyPair::generate_ed25519();
+        write_keypair_file(path, &generated).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(read_keypair_file(path).unwrap().get_peer_id(), generated.get_peer_id());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,75 +1,535 @@
+use chrono::Utc;
 use reqwest;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use crate::{BLOCK_KEY_OFFSET, BLOCK_KEY_DELAY, CURRENT_KEYBLOCK};
+use crate::cfg_handler::RandomxCfg;
+use crate::error::Error;
+use crate::metrics::Metrics;
+use crate::mocks;
+use crate::state::PowState;
+use crate::{BLOCK_KEY_OFFSET, BLOCK_KEY_DELAY};
+
+// where we get the block height from. key block derivation is the same for all chains.
+pub trait KeyBlockSource: Send + Sync {
+    fn name(&self) -> String;
+    fn block_height(&self) -> Result<u64, String>;
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum KeyBlockSourceCfg {
+    Eth { uri: String },
+    Lotus { uri: String },
+    File { path: String },
+    Static { height: u64 },
+    Mock { start_height: u64, block_time_secs: u64 },
+}
+
+// retry, failover and quorum settings for fetching the block height
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct RpcPolicy {
+    // additional endpoints speaking the same protocol as the configured eth or lotus source
+    pub fallback_uris: Vec<String>,
+    pub timeout_ms: u64,
+    pub retries: u32,
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    // number of endpoints that need to agree on the height within tolerance blocks
+    pub quorum: u32,
+    pub tolerance: u64,
+}
+
+impl Default for RpcPolicy {
+    fn default() -> Self {
+        RpcPolicy {
+            fallback_uris: vec![],
+            timeout_ms: 10_000,
+            retries: 3,
+            backoff_base_ms: 500,
+            backoff_max_ms: 30_000,
+            quorum: 1,
+            tolerance: 0,
+        }
+    }
+}
+
+// a new key block every offset blocks. it's taken into use once the chain is past it and it's a multiple
+// of delay, which needs to divide offset.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default)]
+pub struct KeyBlockSchedule {
+    pub offset: u64,
+    pub delay: u64,
+}
+
+impl Default for KeyBlockSchedule {
+    fn default() -> Self {
+        KeyBlockSchedule {
+            offset: BLOCK_KEY_OFFSET as u64,
+            delay: BLOCK_KEY_DELAY as u64,
+        }
+    }
+}
+
+impl RpcPolicy {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    // exponential backoff for the nth consecutive failure, starting at n = 1
+    fn backoff_ms(&self, n: u32) -> u64 {
+        let exp = std::cmp::min(n.saturating_sub(1), 20);
+        std::cmp::min(self.backoff_base_ms.saturating_mul(1 << exp), self.backoff_max_ms)
+    }
+}
+
+// falls back to eth json-rpc against key_blockchain_uri if no source is configured. rpc latencies and
+// errors are recorded to metrics.
+pub fn source_from_cfg(cfg: &RandomxCfg, metrics: Arc<Metrics>) -> Box<dyn KeyBlockSource> {
+    let source_cfg = match cfg.key_block_source {
+        Some(ref s) => s.clone(),
+        None => KeyBlockSourceCfg::Eth { uri: cfg.key_blockchain_uri.clone() },
+    };
+    let policy = cfg.rpc.clone();
+    let timeout = policy.timeout();
+    let endpoints: Vec<Box<dyn KeyBlockSource>> = match source_cfg {
+        KeyBlockSourceCfg::Eth { uri } => std::iter::once(uri)
+            .chain(policy.fallback_uris.iter().cloned())
+            .map(|uri| Box::new(EthRpcSource { uri, timeout }) as Box<dyn KeyBlockSource>)
+            .collect(),
+        KeyBlockSourceCfg::Lotus { uri } => std::iter::once(uri)
+            .chain(policy.fallback_uris.iter().cloned())
+            .map(|uri| Box::new(LotusRpcSource { uri, timeout }) as Box<dyn KeyBlockSource>)
+            .collect(),
+        KeyBlockSourceCfg::File { path } => vec![Box::new(FileSource { path })],
+        KeyBlockSourceCfg::Static { height } => vec![Box::new(StaticSource { height })],
+        KeyBlockSourceCfg::Mock { start_height, block_time_secs } => vec![Box::new(mocks::MockBlockSource::new(start_height, block_time_secs))],
+    };
+    Box::new(FailoverSource::new(endpoints, policy, metrics))
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct JSONRPCResponse {
     jsonrpc: String,
     id: u32,
-    result: String,
+    #[serde(default)]
+    result: Value,
+    #[serde(default)]
+    error: Option<Value>,
 }
 
-// json-rpc call 
-fn fetch_block_height(chain_uri: &str) -> Result<u64, &'static str> {
-    let client = reqwest::blocking::Client::new();
+// json-rpc call
+pub fn json_rpc_call(uri: &str, method: &str, params: Value, timeout: Duration) -> Result<Value, String> {
+    let client = match reqwest::blocking::Client::builder().timeout(timeout).build() {
+        Ok(c) => c,
+        Err(e) => { return Err(format!("can't build rpc client: {}", e)); }
+    };
     let res = client
-        .post(chain_uri)
+        .post(uri)
         .header("Content-Type", "application/json")
         .header("Accept", "application/json")
         .json(&serde_json::json!({
             "jsonrpc": "2.0",
-            "method": "eth_blockNumber",
-            "params": [],
+            "method": method,
+            "params": params,
             "id": 1,
         }))
-        .send()
-        .unwrap()
-        .json::<JSONRPCResponse>();
-
-    match res {
-        Ok(res) => {
-            let block = u64::from_str_radix(&res.result[2..], 16).unwrap();
-            Ok(block)
+        .send();
+
+    let res = match res {
+        Ok(r) => r,
+        Err(e) => { return Err(format!("{} request to {} failed: {}", method, uri, e)); }
+    };
+    match res.json::<JSONRPCResponse>() {
+        Ok(JSONRPCResponse { error: Some(e), .. }) => Err(format!("{} returned error: {}", method, e)),
+        Ok(r) => Ok(r.result),
+        Err(e) => Err(format!("invalid {} response from {}: {}", method, uri, e)),
+    }
+}
+
+fn parse_eth_block_number(result: &Value) -> Result<u64, String> {
+    let hex_height = match result.as_str() {
+        Some(h) => h,
+        None => { return Err(format!("unexpected eth_blockNumber result: {}", result)); }
+    };
+    match u64::from_str_radix(hex_height.trim_start_matches("0x"), 16) {
+        Ok(b) => Ok(b),
+        Err(e) => Err(format!("invalid block number {}: {}", hex_height, e)),
+    }
+}
+
+fn parse_lotus_height(result: &Value) -> Result<u64, String> {
+    match result.get("Height").and_then(|h| h.as_u64()) {
+        Some(h) => Ok(h),
+        None => Err("Filecoin.ChainHead result has no Height".to_string()),
+    }
+}
+
+// ethereum style json-rpc, including fevm endpoints
+pub struct EthRpcSource {
+    pub uri: String,
+    pub timeout: Duration,
+}
+
+impl KeyBlockSource for EthRpcSource {
+    fn name(&self) -> String {
+        format!("eth:{}", self.uri)
+    }
+
+    fn block_height(&self) -> Result<u64, String> {
+        let result = json_rpc_call(&self.uri, "eth_blockNumber", serde_json::json!([]), self.timeout)?;
+        parse_eth_block_number(&result)
+    }
+}
+
+// filecoin lotus json-rpc
+pub struct LotusRpcSource {
+    pub uri: String,
+    pub timeout: Duration,
+}
+
+impl KeyBlockSource for LotusRpcSource {
+    fn name(&self) -> String {
+        format!("lotus:{}", self.uri)
+    }
+
+    fn block_height(&self) -> Result<u64, String> {
+        let result = json_rpc_call(&self.uri, "Filecoin.ChainHead", serde_json::json!([]), self.timeout)?;
+        parse_lotus_height(&result)
+    }
+}
+
+// block height as decimal in a file for air-gapped testing. the file is re-read on every call.
+pub struct FileSource {
+    pub path: String,
+}
+
+impl KeyBlockSource for FileSource {
+    fn name(&self) -> String {
+        format!("file:{}", self.path)
+    }
+
+    fn block_height(&self) -> Result<u64, String> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(e) => { return Err(format!("can't read block height file {}: {}", self.path, e)); }
+        };
+        match content.trim().parse::<u64>() {
+            Ok(h) => Ok(h),
+            Err(e) => Err(format!("invalid block height in {}: {}", self.path, e)),
         }
-        Err(_) => Err("error"),
     }
 }
 
+pub struct StaticSource {
+    pub height: u64,
+}
+
+impl KeyBlockSource for StaticSource {
+    fn name(&self) -> String {
+        format!("static:{}", self.height)
+    }
+
+    fn block_height(&self) -> Result<u64, String> {
+        Ok(self.height)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EndpointHealth {
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    pub last_error: Option<String>,
+    // millis timestamp before which the endpoint is skipped
+    pub retry_after: i64,
+}
+
+// queries endpoints in order, skipping the ones backing off after failures, until quorum endpoints
+// agree on the height. the whole round is retried with exponential backoff.
+pub struct FailoverSource {
+    endpoints: Vec<Box<dyn KeyBlockSource>>,
+    health: Mutex<Vec<EndpointHealth>>,
+    policy: RpcPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl FailoverSource {
+    pub fn new(endpoints: Vec<Box<dyn KeyBlockSource>>, policy: RpcPolicy, metrics: Arc<Metrics>) -> Self {
+        let health = Mutex::new(vec![EndpointHealth::default(); endpoints.len()]);
+        FailoverSource { endpoints, health, policy, metrics }
+    }
+
+    pub fn health(&self) -> Vec<(String, EndpointHealth)> {
+        let health = self.health.lock().unwrap();
+        self.endpoints.iter().map(|e| e.name()).zip(health.iter().cloned()).collect()
+    }
+
+    fn record(&self, idx: usize, res: &Result<u64, String>) {
+        let mut health = self.health.lock().unwrap();
+        let h = &mut health[idx];
+        match res {
+            Ok(_) => {
+                h.consecutive_failures = 0;
+                h.retry_after = 0;
+            }
+            Err(e) => {
+                h.consecutive_failures += 1;
+                h.total_failures += 1;
+                h.last_error = Some(e.clone());
+                h.retry_after = Utc::now().timestamp_millis() + self.policy.backoff_ms(h.consecutive_failures) as i64;
+            }
+        }
+    }
+
+    fn query_round(&self) -> Result<u64, String> {
+        let now = Utc::now().timestamp_millis();
+        let quorum = std::cmp::max(1, self.policy.quorum) as usize;
+        let backing_off: Vec<bool> = self.health.lock().unwrap().iter().map(|h| h.retry_after > now).collect();
+        // if everyone is backing off we try them all rather than not at all
+        let all_backing_off = backing_off.iter().all(|b| *b);
+
+        let mut heights: Vec<u64> = vec![];
+        let mut errors: Vec<String> = vec![];
+        for (idx, endpoint) in self.endpoints.iter().enumerate() {
+            if backing_off[idx] && !all_backing_off {
+                continue;
+            }
+            let started = std::time::Instant::now();
+            let res = endpoint.block_height();
+            self.metrics.record_rpc(&endpoint.name(), started.elapsed(), res.is_ok());
+            self.record(idx, &res);
+            match res {
+                Ok(h) => heights.push(h),
+                Err(e) => {
+                    log::warn!("block height from {} failed: {}", endpoint.name(), e);
+                    errors.push(e);
+                }
+            }
+            if let Some(h) = quorum_height(&heights, quorum, self.policy.tolerance) {
+                return Ok(h);
+            }
+        }
+        if heights.is_empty() {
+            return Err(format!("all endpoints failed: {}", errors.join("; ")));
+        }
+        Err(format!("no quorum of {} within {} blocks among heights {:?}", quorum, self.policy.tolerance, heights))
+    }
+}
+
+impl KeyBlockSource for FailoverSource {
+    fn name(&self) -> String {
+        let names: Vec<String> = self.endpoints.iter().map(|e| e.name()).collect();
+        format!("[{}] quorum {}", names.join(", "), self.policy.quorum)
+    }
+
+    fn block_height(&self) -> Result<u64, String> {
+        let mut last_err = String::new();
+        for attempt in 0..=self.policy.retries {
+            if attempt > 0 {
+                thread::sleep(Duration::from_millis(self.policy.backoff_ms(attempt)));
+            }
+            match self.query_round() {
+                Ok(h) => return Ok(h),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(format!("giving up after {} retries: {}", self.policy.retries, last_err))
+    }
+}
+
+// highest height at least quorum heights agree on, i.e., lie within tolerance of it.
+// a single endpoint running ahead or behind can't move the result.
+fn quorum_height(heights: &[u64], quorum: usize, tolerance: u64) -> Option<u64> {
+    if heights.len() < quorum {
+        return None;
+    }
+    let mut sorted = heights.to_vec();
+    sorted.sort_unstable();
+    sorted
+        .iter()
+        .rev()
+        .find(|h| sorted.iter().filter(|o| o.abs_diff(**h) <= tolerance).count() >= quorum)
+        .copied()
+}
+
 // get key block from block height
-fn make_keyblock(block_height: &u64) -> u64 {
-    let key_block: u64;
+fn make_keyblock(block_height: &u64, schedule: &KeyBlockSchedule) -> u64 {
     let block_num = std::cmp::max(1, *block_height);
-    let rem = block_num % BLOCK_KEY_OFFSET as u64;
+    let rem = block_num % schedule.offset;
     if rem == 0 {
-        key_block = std::cmp::max(1, block_num - BLOCK_KEY_OFFSET as u64);
+        std::cmp::max(1, block_num - schedule.offset)
     } else {
-        key_block = std::cmp::max(1, block_num - rem);
+        std::cmp::max(1, block_num - rem)
     }
-    key_block
 }
 
-fn updated_keyblock(block_height: u64, key_block: u64) -> bool {
-    if key_block % BLOCK_KEY_OFFSET as u64 == 0 && block_height > key_block && key_block % BLOCK_KEY_DELAY as u64 == 0 {
-        if CURRENT_KEYBLOCK.load(Relaxed) < key_block {
-            CURRENT_KEYBLOCK.swap(key_block, Relaxed);
+// key blocks are predictable, which lets us prepare the next datasets ahead of time
+pub fn next_keyblock(key_block: &u64, schedule: &KeyBlockSchedule) -> u64 {
+    (key_block / schedule.offset + 1) * schedule.offset
+}
+
+#[allow(clippy::manual_is_multiple_of)]
+fn updated_keyblock(state: &PowState, block_height: u64, key_block: u64) -> bool {
+    let schedule = &state.key_block_schedule;
+    if key_block % schedule.offset == 0 && block_height > key_block && key_block % schedule.delay == 0 {
+        if state.current_keyblock.load(Relaxed) < key_block {
+            state.current_keyblock.swap(key_block, Relaxed);
                 return true;
         }
     }
-    else if key_block < BLOCK_KEY_OFFSET as u64 && key_block > CURRENT_KEYBLOCK.load(Relaxed) {
-        CURRENT_KEYBLOCK.swap(key_block, Relaxed);
+    else if key_block < schedule.offset && key_block > state.current_keyblock.load(Relaxed) {
+        state.current_keyblock.swap(key_block, Relaxed);
         return true;
     }
     false
 }
 
-pub fn keyblock_handler(chain_uri: &str) -> Result<(u64, bool), ()> {
-    
-    let block_height = fetch_block_height(chain_uri).unwrap();
-    let key_block = make_keyblock(&block_height);
-    let update = updated_keyblock(block_height, key_block);
+pub fn keyblock_handler(source: &dyn KeyBlockSource, state: &PowState) -> Result<(u64, bool), Error> {
+
+    let block_height = match source.block_height() {
+        Ok(h) => h,
+        Err(e) => { return Err(Error::rpc(source.name(), e)); }
+    };
+    state.current_block_height.swap(block_height, Relaxed);
+    let key_block = make_keyblock(&block_height, &state.key_block_schedule);
+    let update = updated_keyblock(state, block_height, key_block);
 
     Ok((key_block, update))
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_height_test() {
+        assert_eq!(parse_eth_block_number(&serde_json::json!("0x30d4ac")), Ok(3_200_172));
+        assert!(parse_eth_block_number(&serde_json::json!(null)).is_err());
+        assert!(parse_eth_block_number(&serde_json::json!("0xzz")).is_err());
+
+        let head = serde_json::json!({"Cids": [], "Blocks": [], "Height": 3_200_172});
+        assert_eq!(parse_lotus_height(&head), Ok(3_200_172));
+        assert!(parse_lotus_height(&serde_json::json!({})).is_err());
+    }
+
+    struct FlakySource {
+        height: Result<u64, String>,
+        calls: Mutex<u32>,
+    }
+
+    impl KeyBlockSource for FlakySource {
+        fn name(&self) -> String {
+            "flaky".to_string()
+        }
+
+        fn block_height(&self) -> Result<u64, String> {
+            *self.calls.lock().unwrap() += 1;
+            self.height.clone()
+        }
+    }
+
+    fn flaky(height: Result<u64, String>) -> Box<dyn KeyBlockSource> {
+        Box::new(FlakySource { height, calls: Mutex::new(0) })
+    }
+
+    fn fast_policy(quorum: u32, tolerance: u64) -> RpcPolicy {
+        RpcPolicy { retries: 1, backoff_base_ms: 1, backoff_max_ms: 1, quorum, tolerance, ..RpcPolicy::default() }
+    }
+
+    #[test]
+    fn quorum_height_test() {
+        assert_eq!(quorum_height(&[100], 1, 0), Some(100));
+        assert_eq!(quorum_height(&[100, 5000], 2, 2), None);
+        assert_eq!(quorum_height(&[100, 101, 5000], 2, 2), Some(101));
+        assert_eq!(quorum_height(&[100, 101, 102], 3, 2), Some(102));
+        assert_eq!(quorum_height(&[100, 101], 3, 2), None);
+    }
+
+    #[test]
+    fn failover_test() {
+        let source = FailoverSource::new(vec![flaky(Err("down".to_string())), flaky(Ok(4100))], fast_policy(1, 0), Arc::default());
+        assert_eq!(source.block_height(), Ok(4100));
+        let health = source.health();
+        assert_eq!(health[0].1.consecutive_failures, 1);
+        assert_eq!(health[1].1.consecutive_failures, 0);
+
+        let source = FailoverSource::new(vec![flaky(Err("down".to_string())), flaky(Err("down".to_string()))], fast_policy(1, 0), Arc::default());
+        assert!(source.block_height().is_err());
+        assert_eq!(source.health()[0].1.total_failures, 2);
+
+        let source = FailoverSource::new(vec![flaky(Ok(4100)), flaky(Ok(9000)), flaky(Ok(4101))], fast_policy(2, 1), Arc::default());
+        assert_eq!(source.block_height(), Ok(4101));
+        let source = FailoverSource::new(vec![flaky(Ok(4100)), flaky(Ok(9000))], fast_policy(2, 1), Arc::default());
+        assert!(source.block_height().is_err());
+    }
+
+    #[test]
+    fn backoff_test() {
+        let policy = RpcPolicy::default();
+        assert_eq!(policy.backoff_ms(1), 500);
+        assert_eq!(policy.backoff_ms(3), 2_000);
+        assert_eq!(policy.backoff_ms(64), 30_000);
+    }
+
+    #[test]
+    fn file_source_test() {
+        let path = std::env::temp_dir().join(format!("randomx_height_test_{}", std::process::id()));
+        let source = FileSource { path: path.to_str().unwrap().to_string() };
+        assert!(source.block_height().is_err());
+
+        fs::write(&path, "4100\n").unwrap();
+        assert_eq!(source.block_height(), Ok(4100));
+        let schedule = KeyBlockSchedule::default();
+        assert_eq!(make_keyblock(&source.block_height().unwrap(), &schedule), 4096);
+        assert_eq!(next_keyblock(&4096, &schedule), 6144);
+        assert_eq!(next_keyblock(&1, &schedule), 2048);
+        let devnet = KeyBlockSchedule { offset: 64, delay: 8 };
+        assert_eq!(make_keyblock(&4100, &devnet), 4096);
+        assert_eq!(next_keyblock(&4096, &devnet), 4160);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn source_cfg_test() {
+        let cfg: KeyBlockSourceCfg = serde_json::from_str(r#"{"type": "lotus", "uri": "http://127.0.0.1:1234/rpc/v1"}"#).unwrap();
+        assert_eq!(cfg, KeyBlockSourceCfg::Lotus { uri: "http://127.0.0.1:1234/rpc/v1".to_string() });
+        let cfg: KeyBlockSourceCfg = serde_json::from_str(r#"{"type": "static", "height": 4096}"#).unwrap();
+        assert_eq!(cfg, KeyBlockSourceCfg::Static { height: 4096 });
+    }
+
+    #[test]
+    fn concurrent_keyblock_test() {
+        // each state follows its own chain, updates on one must not leak into the other
+        let run = |heights: Vec<u64>| {
+            thread::spawn(move || {
+                let path = std::env::temp_dir().join(format!("randomx_kb_nonces_{}_{}", std::process::id(), heights[0]));
+                let state = PowState::new(path.to_str().unwrap());
+                let mut updates = vec![];
+                for height in heights {
+                    let (key_block, updated) = keyblock_handler(&StaticSource { height }, &state).unwrap();
+                    updates.push((key_block, updated));
+                    assert_eq!(state.current_block_height.load(Relaxed), height);
+                }
+                (state.current_keyblock.load(Relaxed), updates)
+            })
+        };
+        let a = run(vec![100, 4100, 4200, 6200, 4100]);
+        let b = run(vec![10, 2100]);
+
+        let (key_block, updates) = a.join().unwrap();
+        assert_eq!(key_block, 6144);
+        assert_eq!(updates, vec![(1, false), (4096, true), (4096, false), (6144, true), (4096, false)]);
+        let (key_block, updates) = b.join().unwrap();
+        assert_eq!(key_block, 2048);
+        assert_eq!(updates, vec![(1, false), (2048, true)]);
+    }
+}
@@ -0,0 +1,65 @@
+#![feature(lazy_cell)]
+#![feature(file_create_new)]
+use fluence_keypair::KeyPair;
+use std::sync::{Arc, LazyLock, OnceLock};
+
+// RandomX PoW for Fluence Proof of Capacity. embed it with controller::Controller, the randomx-wrapper
+// binary is a thin wrapper around it.
+//
+// each controller owns its pool, key block, nonce, precompute and metrics state (state::PowState), only the
+// paths and the operator keypair below are process-wide.
+
+pub mod bench_handler;
+pub mod cfg_handler;
+pub mod cfg_watcher;
+pub mod check_handler;
+pub mod cli_handler;
+pub mod compute_units;
+pub mod control_handler;
+pub mod controller;
+pub mod error;
+mod hashers;
+pub mod key_handler;
+pub mod keyblock;
+pub mod metrics;
+pub mod mocks;
+pub mod nonce;
+pub mod pow;
+pub mod precompute;
+pub mod puzzle;
+pub mod pid_handler;
+pub mod signal_handler;
+pub mod state;
+pub mod verifier;
+pub mod verify_handler;
+
+pub use controller::Controller;
+pub use error::Error;
+
+
+pub const KEYBLOCK_CHECK_INTERVAL: u32 = 30 * 60 * 1000; // in millis
+pub const MAIN_LOOP_SLEEP: u32 = 6 * 1_000; // in millis
+pub const DEALLOC_TIMEOUT: u32 = 60 * 1_000; // in millis
+pub const SHUTDOWN_TIMEOUT: u32 = 30 * 1_000; // in millis
+const BLOCK_KEY_OFFSET: u32 = 2_048;
+const BLOCK_KEY_DELAY: u32 = 64;
+
+// set once from the command line, defaults to the layout under the working dir
+static PATHS: OnceLock<cli_handler::Paths> = OnceLock::new();
+
+// set once from the setup config before any randomx instance is started
+static KEYPAIR: OnceLock<Arc<KeyPair>> = OnceLock::new();
+static PEERID: LazyLock<Arc<String>> =
+    LazyLock::new(|| Arc::new(KEYPAIR.get().expect("keypair not loaded").get_peer_id().to_base58()));
+
+pub fn paths() -> &'static cli_handler::Paths {
+    PATHS.get_or_init(cli_handler::Paths::default)
+}
+
+// needs to be called before anything reads a path
+pub fn set_paths(paths: cli_handler::Paths) -> Result<(), String> {
+    match PATHS.set(paths) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("paths are already set".to_string()),
+    }
+}
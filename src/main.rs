@@ -1,83 +1,33 @@
-#![feature(lazy_cell)]
 #![feature(file_create_new)]
-use cfg_handler::RandomxCfg;
-use cfg_handler::RuntimeCfg;
-use chrono::{Local, Utc};
-use crossbeam::channel::{unbounded, Receiver, Sender};
-use fluence_keypair::KeyPair;
+use chrono::Local;
+use crossbeam::channel::{never, unbounded, Receiver, RecvTimeoutError};
 use log::*;
+use randomx_wrapper::cli_handler::{self, Command};
+use randomx_wrapper::control_handler::{self, ControlCmd};
+use randomx_wrapper::signal_handler::{self, AppSignal};
+use randomx_wrapper::{bench_handler, cfg_handler, cfg_watcher, check_handler, key_handler, paths, pid_handler, verify_handler, Controller, Error};
+use randomx_wrapper::{MAIN_LOOP_SLEEP, SHUTDOWN_TIMEOUT};
 use std::fs::File;
 use std::io::Write;
-use std::sync::{
-    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::Relaxed},
-    Arc, LazyLock, Mutex, RwLock,
-};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+// randomx-wrapper binary. the PoW itself lives in the library, see controller.rs.
 
-mod cfg_handler;
-mod hashers;
-mod keyblock;
-mod mocks;
-mod pow;
-mod puzzle;
-mod pid_handler;
-
-
-const LOG_PATH: &str = "./logs/log.txt";
-const PID_PATH: &str = "./pid.json";
-const SETUP_CFG_PATH: &str = "./data/randomx_cfg.json";
-const RUNTME_CFG_PATH: &str = "./data/runtime_cfg.json";
-const PUZZLE_SOLUTION_DIR: &str = "./puzzle-solutions/";
-const KEYBLOCK_CHECK_INTERVAL: u32 = 30 * 60 * 1000; // in millis
-const MAIN_LOOP_SLEEP: u32 = 6 * 1_000; // in millis
-const BLOCK_KEY_OFFSET: u32 = 2_048;
-const BLOCK_KEY_DELAY: u32 = 64;
-
-static MAX_THREAD_COUNT: AtomicU32 = AtomicU32::new(0);
-static ALLOC_THREAD_COUNT: AtomicU32 = AtomicU32::new(0);
-
-static CURRENT_KEYBLOCK: AtomicU64 = AtomicU64::new(1);
-
-static PUZZLE_DIFFICULTY:AtomicU32 = AtomicU32::new(100);
-
-static APP_EXIT: AtomicBool = AtomicBool::new(false);
-static RANDOMX_RESTART: AtomicBool = AtomicBool::new(false);
-
-static KEYPAIR: LazyLock<Arc<KeyPair>> = LazyLock::new(|| Arc::new(KeyPair::generate_ed25519()));
-static PEERID: LazyLock<Arc<String>> =
-    LazyLock::new(|| Arc::new(KEYPAIR.get_peer_id().to_base58()));
-
-fn golden_hash_processor() -> thread::JoinHandle<()> {
-    let thread_handle = thread::spawn(move || {
-        let mut last_rpc_call = Utc::now().timestamp_millis();
-
-        loop {
-            // check for keyblock updates every x seconds
-            if Utc::now().timestamp_millis() - last_rpc_call > (KEYBLOCK_CHECK_INTERVAL as i64) {
-                // run key block updater
-                last_rpc_call = Utc::now().timestamp_millis();
-            }
-
-            // check for exit updates every x seconds
-
-            // check for allocation changes
-        }
-    });
-    thread_handle
-}
-
-fn setup_logging() {
-    let file = match File::create_new(LOG_PATH) {
+fn setup_logging() -> Result<(), Error> {
+    let file = match File::create_new(&paths().log) {
         Ok(f) => f,
-        Err(_) => File::options().append(true).open(LOG_PATH).unwrap(),
+        Err(_) => match File::options().append(true).open(&paths().log) {
+            Ok(f) => f,
+            Err(e) => { return Err(Error::io(format!("can't open log {}", paths().log), e)); }
+        },
     };
     let target = Box::new(file);
 
     env_logger::Builder::new()
         .target(env_logger::Target::Pipe(target))
-        .filter(None, LevelFilter::Debug)
+        // the actual level is set from the setup config and can be changed on reload
+        .filter(None, LevelFilter::Trace)
         .format(|buf, record| {
             writeln!(
                 buf,
@@ -90,258 +40,281 @@ fn setup_logging() {
             )
         })
         .init();
+    Ok(())
 }
 
-fn global_config_setter(app_cfg: &RandomxCfg, runtime_cfg: &RuntimeCfg) -> Result<(), ()> {
-    let t_max_alloc = app_cfg.num_cores * app_cfg.threads_per_core;
-    MAX_THREAD_COUNT.swap(t_max_alloc, Relaxed);
+// socket from the command line or env, otherwise from the setup config
+fn control_socket(socket: Option<String>) -> Option<String> {
+    match socket {
+        Some(s) => Some(s),
+        None => cfg_handler::RandomxCfg::from_file().ok().and_then(|cfg| cfg.control_socket),
+    }
+}
 
-    let t_dealloc = runtime_cfg.deallocated_threads;
-    if t_dealloc < MAX_THREAD_COUNT.load(Relaxed) {
-        let actual_alloc = MAX_THREAD_COUNT.load(Relaxed) - t_dealloc;
-        ALLOC_THREAD_COUNT.swap(actual_alloc, Relaxed);
-    } else {
-        log::error!("invalid thread de-allocation: {}. dealloc request ignored. use ctrl-c to shut down app.", t_dealloc);
+fn run_control_client(socket: Option<String>, cmd: ControlCmd) -> i32 {
+    match control_socket(socket) {
+        Some(path) => control_handler::run_client(&path, &cmd),
+        None => {
+            eprintln!("no control socket. pass --socket or set control_socket in {}", paths().setup_cfg);
+            2
+        }
     }
+}
 
-    PUZZLE_DIFFICULTY.swap(app_cfg.difficulty, Relaxed);
-    Ok(())
+// returns true on shutdown
+fn handle_signal(controller: &mut Controller, sig: AppSignal) -> bool {
+    match sig {
+        AppSignal::Shutdown => {
+            log::info!("received sigterm signal ... shutting down.");
+            println!("received sigterm signal and initiated shut down. This takes a minute ... patience.");
+            return true;
+        }
+        AppSignal::Reload => {
+            log::info!("received sighup signal ... reloading {}.", paths().setup_cfg);
+            let res = cfg_handler::RandomxCfg::load(&paths().setup_cfg)
+                .map_err(|e| e.to_string())
+                .and_then(|cfg| controller.reload(cfg));
+            if let Err(e) = res {
+                log::error!("setup config reload rejected: {}", e);
+            }
+        }
+        AppSignal::Status => {
+            let status = controller.execute(&ControlCmd::Status);
+            log::info!("status snapshot: {}", serde_json::to_string(&status).unwrap_or_default());
+        }
+    }
+    false
+}
+
+// forces the exit if the shutdown doesn't complete in time or on another sigterm
+fn spawn_shutdown_watchdog(sig_rx: Receiver<AppSignal>) {
+    thread::spawn(move || {
+        let grace = Duration::from_millis(SHUTDOWN_TIMEOUT as u64 + MAIN_LOOP_SLEEP as u64);
+        let deadline = Instant::now() + grace;
+        loop {
+            match sig_rx.recv_deadline(deadline) {
+                Ok(AppSignal::Shutdown) => {
+                    log::error!("received another sigterm signal ... forcing exit.");
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // without a signal handler only the deadline is left to wait for
+                    if e == RecvTimeoutError::Disconnected {
+                        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                    }
+                    log::error!("shutdown didn't complete within {} millis ... forcing exit.", grace.as_millis());
+                    break;
+                }
+            }
+        }
+        pid_handler::rm_pid();
+        std::process::exit(1);
+    });
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cli = match cli_handler::parse(&args, &|var| std::env::var(var).ok()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli_handler::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let _ = randomx_wrapper::set_paths(cli.paths);
+
+    // only run touches the miner's state, e.g., pid file and log
+    let exit_code = match cli.command {
+        Command::Run { socket } => match run(socket) {
+            Ok(_) => 0,
+            Err(e) => {
+                log::error!("exiting: {}. {}.", e, e.hint());
+                eprintln!("{}. {}. see log {}", e, e.hint(), paths().log);
+                pid_handler::rm_pid();
+                e.exit_code()
+            }
+        },
+        Command::Verify { json, files } => verify_handler::run(json, &files),
+        Command::Bench { seconds, threads, light } => bench_handler::run(seconds, threads, light),
+        Command::Keygen { out } => key_handler::keygen(out.as_deref()),
+        Command::CheckConfig => check_handler::run(paths()),
+        Command::Status { socket } => run_control_client(socket, ControlCmd::Status),
+        Command::Dealloc { socket, count, thread_ids } => run_control_client(socket, ControlCmd::Dealloc { count, thread_ids }),
+        Command::Help => {
+            println!("{}", cli_handler::USAGE);
+            0
+        }
+    };
+    std::process::exit(exit_code);
+}
+
+// errors are logged and mapped to the exit code by main. socket overrides control_socket of the setup config.
+fn run(socket: Option<String>) -> Result<(), Error> {
+    paths().create_dirs()?;
 
     // handle pid file
     pid_handler::rm_pid();
-    pid_handler::write_pid();
+    pid_handler::write_pid()?;
     
-    setup_logging();
+    setup_logging()?;
 
-    //ctrlc and limited sigterm catcher
-    let (crlc_tx, crlc_rx) = unbounded();
-    ctrlc::set_handler(move || crlc_tx.send(()).expect("Could not send signal on channel."))
-        .expect("Error setting Ctrl-C handler");
-    log::info!("crlc channel is up.");
+    // sighup, sigusr1, sigterm and ctrl-c
+    let (sig_tx, sig_rx) = unbounded::<AppSignal>();
+    signal_handler::spawn_signal_handler(sig_tx)?;
+    log::info!("signal channel is up.");
 
 
     // get and set randomx config
-    let app_cfg = cfg_handler::RandomxCfg::from_file().unwrap();
-    let runtime_cfg = cfg_handler::RuntimeCfg::from_file().unwrap();
-    global_config_setter(&app_cfg, &runtime_cfg).unwrap();
-    log::info!("global config updated.");
-
-    // get and set keyblock
-    let (_, _) = keyblock::keyblock_handler(&app_cfg.key_blockchain_uri).unwrap();
-
-
-    // randomx channel to communicate puzzle solution for further processing such as proof generation
-    let (tx, rx): (
-        Sender<puzzle::PuzzleSolution>,
-        Receiver<puzzle::PuzzleSolution>,
-    ) = unbounded();
-    log::info!("RandomX channel is up.");
-
-
-    // setup and fire up the threaded randomx instances
-    let alloc_threads: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::<String>::new()));
-    let dealloc_threads: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::<String>::new()));
-    let dealloc_requests: Arc<RwLock<u32>> = Arc::new(RwLock::<u32>::new(0));
-    let randomx_up_counter: Arc<RwLock<u32>> = Arc::new(RwLock::<u32>::new(0));
-
-    let mut thread_handler:Vec<thread::JoinHandle<()>>;
-
-    // start initiating threads
-    thread_handler = pow::randomx_thread_pool_handler( 
-        MAX_THREAD_COUNT.load(Relaxed),
-        CURRENT_KEYBLOCK.load(Relaxed),
-        tx.clone(),
-        &alloc_threads, 
-        &dealloc_threads, 
-        &dealloc_requests,
-        &randomx_up_counter,
-        None
-    ).unwrap();
-   
-    log::info!("setup done.");
-
-    log::info!("waiting for randomx disk to be initiated for each instance. This takes a while.");
-    loop {
-        match randomx_up_counter.read() {
-            Ok(r) => {
-                if *r == ALLOC_THREAD_COUNT.load(Relaxed) {
-                    break;
-                }
-            },
-            Err(_) => {thread::sleep(Duration::from_millis(5_000));}
-        }
+    let app_cfg = cfg_handler::RandomxCfg::from_file()?;
+    log::set_max_level(app_cfg.log_level());
+    let runtime_cfg = cfg_handler::RuntimeCfg::from_file()?;
+
+    // load operator keypair -- peer id and all signatures derive from it
+    let keypair = key_handler::keypair_from_cfg(&app_cfg)?;
+
+    let control_socket_path = socket.or_else(|| app_cfg.control_socket.clone());
+    let mut controller = Controller::new(app_cfg, keypair, 0)?;
+    // checked against the controller's compute units. compute units from the on-chain registry are only
+    // known once started and the controller checks then.
+    if let Err(e) = controller.set_start_dealloc(runtime_cfg.deallocated_threads) {
+        log::error!("invalid thread de-allocation: {}. dealloc request ignored. use ctrl-c to shut down app.", e);
     }
-    log::info!("{} randomx disks are initiated.", *randomx_up_counter.read().unwrap());
-    
-    // set key block getting time -- good enough
-    let mut last_rpc_call = Utc::now().timestamp_millis();
+    log::info!("global config updated. keypair loaded for peer id {}.", controller.peer_id());
 
-    //main monitoring loop -- trying to preserve threads for randomx
-    log::info!("entering main control loop.");
+    // we can't mine without a key block so we wait for the chain to become reachable. anything else won't
+    // go away by waiting.
     loop {
-        // check for key block updates every x seconds
-        if Utc::now().timestamp_millis() - last_rpc_call > (KEYBLOCK_CHECK_INTERVAL as i64) {
-            // run key block updater
-            let (key_block, updated_kb) = keyblock::keyblock_handler(&app_cfg.key_blockchain_uri).unwrap();
-            // we got a new keyblock and need to tear down the randomx instances and initate with new disks
-            if updated_kb {
-                log::info!("got a new key block {} and need to restart randomx threads.", key_block);
-                RANDOMX_RESTART.swap(true, Relaxed);
-                for t in &thread_handler {
-                    t.is_finished();
-                }
-                RANDOMX_RESTART.swap(true, Relaxed);
-
-                let guard = randomx_up_counter.write();
-                if guard.is_ok() {
-                    let mut rw_guard = guard.unwrap();
-                    *rw_guard = 0;
-                }
-
-                thread_handler = pow::randomx_thread_pool_handler( 
-                    alloc_threads.lock().unwrap().len() as u32,
-                    CURRENT_KEYBLOCK.load(Relaxed),
-                    tx.clone(),
-                    &alloc_threads, 
-                    &dealloc_threads, 
-                    &dealloc_requests,
-                    &randomx_up_counter,
-                    None
-                ).unwrap();
-                
-                log::info!("waiting for randomx disk to be initiated for each instance. This takes a while.");
-                loop {
-                    match randomx_up_counter.read() {
-                        Ok(r) => {
-                            if *r == alloc_threads.lock().unwrap().len() as u32 {
-                                break;
-                            }
-                        },
-                        Err(_) => {thread::sleep(Duration::from_millis(5_000));}
+        match controller.start() {
+            Ok(_) => break,
+            Err(e @ Error::Rpc { .. }) => {
+                // reload and status work without a key block, too
+                let signals: Vec<AppSignal> = sig_rx.try_iter().collect();
+                for sig in signals {
+                    if handle_signal(&mut controller, sig) {
+                        log::info!("received sigterm signal before getting a key block ... shutting down.");
+                        pid_handler::rm_pid();
+                        return Ok(());
                     }
                 }
-                log::info!("{} randomx disks are initiated.", *randomx_up_counter.read().unwrap());
+                log::warn!("{}. retrying in {} millis.", e, MAIN_LOOP_SLEEP);
+                thread::sleep(Duration::from_millis(MAIN_LOOP_SLEEP as u64));
             }
-            log::info!("keyblock update check");
-            last_rpc_call = Utc::now().timestamp_millis();
+            Err(e) => { return Err(e); }
         }
+    }
+    log::info!("setup done.");
 
-        // check for allocation changes
-        let run_cfg = cfg_handler::RuntimeCfg::from_file().unwrap();
-        let dealloc_count = dealloc_threads.lock().unwrap().len() as u32;
-        if run_cfg.deallocated_threads > dealloc_count as u32 {
-            if MAX_THREAD_COUNT.load(Relaxed).checked_sub(run_cfg.deallocated_threads).is_none() {
-                log::warn!("invalid thread reduction request for minus {} threads ignored.", run_cfg.deallocated_threads);
+    // control socket commands are executed in the main loop
+    let (ctl_tx, ctl_rx) = unbounded::<control_handler::ControlRequest>();
+    let mut ctl_handle = None;
+    if let Some(ref path) = control_socket_path {
+        match control_handler::spawn_control_listener(path, ctl_tx.clone(), controller.state()) {
+            Ok(h) => {
+                log::info!("control socket is up at {}.", path);
+                ctl_handle = Some(h);
             }
-            else {
-                let thread_decr = run_cfg.deallocated_threads - dealloc_count;
-                log::info!("need to reduce thread count to {} by {} threads", dealloc_count, thread_decr);
-                let dealloc = dealloc_requests.write();
-                let mut rw_guard = dealloc.unwrap();
-                *rw_guard = thread_decr;
-            } // drop dealloc lock
-            thread::sleep(Duration::from_millis(3_000));
-            let new_alloc_count = alloc_threads.lock().unwrap().len() as u32;
-            let new_dealloc_count = dealloc_threads.lock().unwrap().len() as u32;
-            log::info!("update allocated thread count: {} and deallocated thread count: {}", new_alloc_count,new_dealloc_count);
+            Err(e) => { log::error!("control socket not available: {}", e); }
         }
-        // note we can't realloc more than we deallocated since n_alloc + n_dealloc === MAX_THREADS 
-        else if run_cfg.deallocated_threads < dealloc_count as u32 {
-            let alloc_delta = dealloc_count - run_cfg.deallocated_threads;
-            log::info!("need to increase thread count by {} threads", alloc_delta);
-            let delta_thread_names = dealloc_threads.lock().unwrap().clone();
-            let delta_thread_names:Vec<&str> = delta_thread_names.iter().map(|s| s.as_str()).collect();
-            let delta_thread_names = delta_thread_names[0..alloc_delta as usize].to_vec();
-            let new_thread_handles = pow::randomx_thread_pool_handler(
-            delta_thread_names.len() as u32,
-            CURRENT_KEYBLOCK.load(Relaxed),
-                tx.clone(),
-                &alloc_threads, 
-                &dealloc_threads, 
-                &dealloc_requests,
-                &randomx_up_counter,
-                Some(delta_thread_names.clone()),
-            )
-            .unwrap();
-            log::info!("waiting for additional randomx disks to be initiated. This takes a while.");
-            // thread::sleep(Duration::from_millis(7_000));
-            loop {
-                match randomx_up_counter.read() {
-                    Ok(r) => {
-                        if *r == alloc_threads.lock().unwrap().len() as u32 {
+    }
+
+    // runtime config updates are picked up by the watcher
+    let (rt_tx, rt_rx) = unbounded::<cfg_handler::RuntimeCfg>();
+    let (rt_rx, rt_watcher) = match cfg_watcher::spawn_runtime_cfg_watcher(&paths().runtime_cfg, rt_tx, controller.state()) {
+        Ok(w) => (rt_rx, Some(w)),
+        Err(e) => {
+            log::error!("runtime config changes are not picked up: {}", e);
+            (never(), None)
+        }
+    };
+
+    // we only act on changes to the runtime config so it doesn't override control socket requests
+    let mut last_runtime_dealloc: Option<u32> = Some(runtime_cfg.deallocated_threads);
+
+    // set if a channel we depend on closed, we shut down gracefully and exit with its code
+    let mut failure: Option<Error> = None;
+
+    //main monitoring loop -- trying to preserve threads for randomx
+    log::info!("entering main control loop.");
+    loop {
+        // key block updates and precompute
+        controller.tick();
+
+        log::debug!("alloc vec       : {:?}", controller.alloc());
+        log::debug!("dealloc vec     : {:?}", controller.dealloced());
+
+        // wait for signals, runtime config updates and control commands instead of just sleeping
+        crossbeam::select! {
+            recv(sig_rx) -> sig => {
+                match sig {
+                    Ok(sig) => {
+                        if handle_signal(&mut controller, sig) {
                             break;
                         }
-                        thread::sleep(Duration::from_millis(100));
-                    },
-                    Err(_) => {thread::sleep(Duration::from_millis(2_000));}
+                    }
+                    Err(_) => {
+                        failure = Some(Error::Channel("signal".to_string()));
+                        break;
+                    }
                 }
             }
-            log::info!("A total of {} randomx disks are initiated.", *randomx_up_counter.read().unwrap());
-
-            // clean up dealloc references
-            log::info!("starting cleanup of dealloc tracker.");    
-            let mut dealloc_guard = dealloc_threads.lock().unwrap();
-            for reg_name in delta_thread_names {
-                let v = &mut dealloc_guard;
-                v.retain(|name| name != &reg_name.to_string());
-            }
-            drop(dealloc_guard);
-            thread_handler.extend(new_thread_handles);
-        }
-        println!("alloc vec       : {:?}", alloc_threads.lock().unwrap());
-        println!("dealloc vec     : {:?}", dealloc_threads.lock().unwrap());
-        println!("dealloc requests: {:?}", dealloc_requests.read().unwrap());
-        println!("up counter      : {}", *randomx_up_counter.read().unwrap());
-
-        // process puzzle solutions -- depending on success frequency this could be another thread
-        loop {
-            if !rx.is_empty() {
-                let solution = rx.recv().unwrap();
-                // verify just for the heck of it .. breadcrumps for mike :)
-                // let good_solution = pow::randomx_verifier(&solution.signed_context, &solution.signed_nonce, &solution.difficulty, &solution.hash);
-                match solution.to_file(None) {
-                    Ok(_) => {},
-                    Err(e) => { println!("{}", e);}
+            recv(rt_rx) -> run_cfg => {
+                match run_cfg {
+                    Ok(run_cfg) => {
+                        // a failed request is retried on the next update, even with the same value
+                        if last_runtime_dealloc != Some(run_cfg.deallocated_threads) {
+                            match controller.set_dealloc_target(run_cfg.deallocated_threads) {
+                                Ok(changed) => {
+                                    last_runtime_dealloc = Some(run_cfg.deallocated_threads);
+                                    if !changed.is_empty() {
+                                        log::info!("runtime config reallocated threads {:?}", changed);
+                                    }
+                                }
+                                Err(e) => log::warn!("runtime config dealloc request ignored: {}", e),
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        failure = Some(Error::Channel("runtime config".to_string()));
+                        break;
+                    }
                 }
-            } 
-            else {
-                break;
             }
-        }
-
-        // check for sigterm        
-        if !crlc_rx.is_empty() {
-            match crlc_rx.recv() {
-                Ok(_) => {
-                    log::info!("received sigterm signal ... shutting down.");
-                    println!("received sigterm signal and initiated shut down. This takes a minute ... patience.");
-                    APP_EXIT.swap(true, Relaxed);
-                    break;
+            recv(ctl_rx) -> req => {
+                if let Ok(req) = req {
+                    if controller.handle_control(req) {
+                        log::info!("received shutdown command ... shutting down.");
+                        break;
+                    }
                 }
-                Err(_) => { println!("no crtlc in channel"); }
             }
+            default(Duration::from_millis(MAIN_LOOP_SLEEP as u64)) => {}
         }
-        thread::sleep(Duration::from_millis(MAIN_LOOP_SLEEP as u64));
     }
 
-    // teardown -- might be blocking for SIGTERM
-    let timer_start = Utc::now().timestamp_millis();
-    let max_shutdown_duration: i32 = 15 * 1_000; // 15 seconds to clean things up
-
-    while Utc::now().timestamp_millis() - timer_start < (max_shutdown_duration as i64) {
-        // all threads exited ? log::info!("threads have exited")
-
-        // all channels empty ? log::info!("channels are empty")
+    // teardown -- the controller signals its threads and waits for them up to the shutdown timeout. should
+    // anything else hang, or on another ctrl-c, the watchdog forces the exit.
+    spawn_shutdown_watchdog(sig_rx);
 
-        thread::sleep(Duration::from_millis(100));
+    let summary = controller.stop(Duration::from_millis(SHUTDOWN_TIMEOUT as u64));
+    if let Some(w) = rt_watcher {
+        if w.stop().is_err() {
+            log::error!("runtime config watcher panicked.");
+        }
+    }
+    if let Some(h) = ctl_handle {
+        if h.join().is_err() {
+            log::error!("control listener panicked.");
+        }
     }
-    log::info!("done with interrupt catcher.");
-    // finally join 
-    for t in thread_handler {
-        t.join().unwrap();
+    pid_handler::rm_pid();
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    if !summary.clean() {
+        log::error!("unclean shutdown. threads {:?} didn't stop, solution queue flushed: {}. forcing exit.", summary.unresponsive_threads, summary.flushed);
+        std::process::exit(1);
     }
     log::info!("done and done. exiting main.");
+    Ok(())
 }
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::state::SharedState;
+
+// prometheus metrics in text exposition format, one set per pool, see state.rs. hasher threads get their
+// own atomics so counting a hash is a single uncontended fetch_add.
+
+#[derive(Default)]
+pub struct ThreadMetrics {
+    pub hashes: AtomicU64,
+    pub solutions: AtomicU64,
+    pub dataset_init_ms: AtomicU64,
+}
+
+#[derive(Default, Clone)]
+struct RpcMetrics {
+    requests: u64,
+    errors: u64,
+    latency_ms_sum: u64,
+    last_latency_ms: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    threads: Mutex<HashMap<String, Arc<ThreadMetrics>>>,
+    rpc: Mutex<HashMap<String, RpcMetrics>>,
+    allocated: AtomicU32,
+    deallocated: AtomicU32,
+    key_block: AtomicU64,
+    verified: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Metrics {
+    // counters survive thread restarts, e.g., realloc or key block changes
+    pub fn thread(&self, name: &str) -> Arc<ThreadMetrics> {
+        let mut threads = self.threads.lock().unwrap();
+        Arc::clone(threads.entry(name.to_string()).or_default())
+    }
+
+    pub fn record_rpc(&self, endpoint: &str, latency: Duration, ok: bool) {
+        let mut rpc = self.rpc.lock().unwrap();
+        let m = rpc.entry(endpoint.to_string()).or_default();
+        m.requests += 1;
+        if !ok {
+            m.errors += 1;
+        }
+        m.latency_ms_sum += latency.as_millis() as u64;
+        m.last_latency_ms = latency.as_millis() as u64;
+    }
+
+    pub fn set_allocation(&self, allocated: u32, deallocated: u32) {
+        self.allocated.swap(allocated, Relaxed);
+        self.deallocated.swap(deallocated, Relaxed);
+    }
+
+    pub fn set_key_block(&self, key_block: u64) {
+        self.key_block.swap(key_block, Relaxed);
+    }
+
+    pub fn record_verification(&self, verified: bool) {
+        match verified {
+            true => self.verified.fetch_add(1, Relaxed),
+            false => self.rejected.fetch_add(1, Relaxed),
+        };
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // sorted for stable output
+        let threads: BTreeMap<String, Arc<ThreadMetrics>> = self.threads.lock().unwrap().iter().map(|(k, v)| (k.clone(), Arc::clone(v))).collect();
+        let rpc: BTreeMap<String, RpcMetrics> = self.rpc.lock().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+
+        let _ = writeln!(out, "# HELP randomx_hashes_total RandomX hashes computed per compute unit.");
+        let _ = writeln!(out, "# TYPE randomx_hashes_total counter");
+        for (name, m) in &threads {
+            let _ = writeln!(out, "randomx_hashes_total{{thread=\"{}\"}} {}", name, m.hashes.load(Relaxed));
+        }
+        let _ = writeln!(out, "# HELP randomx_solutions_total Golden hashes found per compute unit.");
+        let _ = writeln!(out, "# TYPE randomx_solutions_total counter");
+        for (name, m) in &threads {
+            let _ = writeln!(out, "randomx_solutions_total{{thread=\"{}\"}} {}", name, m.solutions.load(Relaxed));
+        }
+        let _ = writeln!(out, "# HELP randomx_dataset_init_seconds Duration of the last RandomX dataset initialization per compute unit.");
+        let _ = writeln!(out, "# TYPE randomx_dataset_init_seconds gauge");
+        for (name, m) in &threads {
+            let _ = writeln!(out, "randomx_dataset_init_seconds{{thread=\"{}\"}} {:.3}", name, m.dataset_init_ms.load(Relaxed) as f64 / 1_000.0);
+        }
+
+        let _ = writeln!(out, "# HELP randomx_current_key_block Key block the RandomX instances are seeded with.");
+        let _ = writeln!(out, "# TYPE randomx_current_key_block gauge");
+        let _ = writeln!(out, "randomx_current_key_block {}", self.key_block.load(Relaxed));
+
+        let _ = writeln!(out, "# HELP randomx_rpc_requests_total Block height requests per endpoint.");
+        let _ = writeln!(out, "# TYPE randomx_rpc_requests_total counter");
+        for (endpoint, m) in &rpc {
+            let _ = writeln!(out, "randomx_rpc_requests_total{{endpoint=\"{}\"}} {}", endpoint, m.requests);
+        }
+        let _ = writeln!(out, "# HELP randomx_rpc_errors_total Failed block height requests per endpoint.");
+        let _ = writeln!(out, "# TYPE randomx_rpc_errors_total counter");
+        for (endpoint, m) in &rpc {
+            let _ = writeln!(out, "randomx_rpc_errors_total{{endpoint=\"{}\"}} {}", endpoint, m.errors);
+        }
+        let _ = writeln!(out, "# HELP randomx_rpc_latency_seconds Block height request latency per endpoint.");
+        let _ = writeln!(out, "# TYPE randomx_rpc_latency_seconds summary");
+        for (endpoint, m) in &rpc {
+            let _ = writeln!(out, "randomx_rpc_latency_seconds_sum{{endpoint=\"{}\"}} {:.3}", endpoint, m.latency_ms_sum as f64 / 1_000.0);
+            let _ = writeln!(out, "randomx_rpc_latency_seconds_count{{endpoint=\"{}\"}} {}", endpoint, m.requests);
+        }
+        let _ = writeln!(out, "# HELP randomx_rpc_last_latency_seconds Latency of the last block height request per endpoint.");
+        let _ = writeln!(out, "# TYPE randomx_rpc_last_latency_seconds gauge");
+        for (endpoint, m) in &rpc {
+            let _ = writeln!(out, "randomx_rpc_last_latency_seconds{{endpoint=\"{}\"}} {:.3}", endpoint, m.last_latency_ms as f64 / 1_000.0);
+        }
+
+        let _ = writeln!(out, "# HELP randomx_allocated_units Compute units allocated to PoW.");
+        let _ = writeln!(out, "# TYPE randomx_allocated_units gauge");
+        let _ = writeln!(out, "randomx_allocated_units {}", self.allocated.load(Relaxed));
+        let _ = writeln!(out, "# HELP randomx_deallocated_units Compute units deallocated from PoW.");
+        let _ = writeln!(out, "# TYPE randomx_deallocated_units gauge");
+        let _ = writeln!(out, "randomx_deallocated_units {}", self.deallocated.load(Relaxed));
+
+        let _ = writeln!(out, "# HELP randomx_solutions_verified_total Solutions that passed verification and were persisted.");
+        let _ = writeln!(out, "# TYPE randomx_solutions_verified_total counter");
+        let _ = writeln!(out, "randomx_solutions_verified_total {}", self.verified.load(Relaxed));
+        let _ = writeln!(out, "# HELP randomx_solutions_rejected_total Solutions that failed verification.");
+        let _ = writeln!(out, "# TYPE randomx_solutions_rejected_total counter");
+        let _ = writeln!(out, "randomx_solutions_rejected_total {}", self.rejected.load(Relaxed));
+        out
+    }
+}
+
+fn handle_request(mut stream: TcpStream, metrics: &Metrics) {
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(2_000)));
+    let mut request_line = String::new();
+    if BufReader::new(&stream).read_line(&mut request_line).is_err() {
+        return;
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let response = if request_line.starts_with("GET ") && (path == "/metrics" || path == "/") {
+        let body = metrics.render();
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    let _ = stream.write_all(response.as_bytes());
+}
+
+// serves /metrics on localhost until app exit
+pub fn spawn_metrics_server(port: u16, state: SharedState) -> Result<thread::JoinHandle<()>, String> {
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(l) => l,
+        Err(e) => { return Err(format!("can't bind metrics port {}: {}", port, e)); }
+    };
+    if let Err(e) = listener.set_nonblocking(true) {
+        return Err(format!("can't set metrics listener to non-blocking: {}", e));
+    }
+    let handle = thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        while !state.exiting() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nonblocking(false);
+                    handle_request(stream, &state.metrics);
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                Err(e) => {
+                    log::warn!("metrics accept failed: {}", e);
+                    thread::sleep(Duration::from_millis(1_000));
+                }
+            }
+        }
+    });
+    match handle {
+        Ok(h) => Ok(h),
+        Err(e) => Err(format!("can't spawn metrics thread: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_test() {
+        let metrics = Metrics::default();
+        let t = metrics.thread("aa");
+        t.hashes.fetch_add(3, Relaxed);
+        t.solutions.fetch_add(1, Relaxed);
+        t.dataset_init_ms.swap(19_500, Relaxed);
+        // same counters after a restart
+        metrics.thread("aa").hashes.fetch_add(2, Relaxed);
+        metrics.record_rpc("eth:http://127.0.0.1", Duration::from_millis(250), true);
+        metrics.record_rpc("eth:http://127.0.0.1", Duration::from_millis(750), false);
+        metrics.set_allocation(5, 1);
+        metrics.set_key_block(4096);
+        metrics.record_verification(true);
+        metrics.record_verification(false);
+        metrics.record_verification(false);
+
+        let out = metrics.render();
+        assert!(out.contains("randomx_hashes_total{thread=\"aa\"} 5\n"));
+        assert!(out.contains("randomx_solutions_total{thread=\"aa\"} 1\n"));
+        assert!(out.contains("randomx_dataset_init_seconds{thread=\"aa\"} 19.500\n"));
+        assert!(out.contains("randomx_rpc_requests_total{endpoint=\"eth:http://127.0.0.1\"} 2\n"));
+        assert!(out.contains("randomx_rpc_errors_total{endpoint=\"eth:http://127.0.0.1\"} 1\n"));
+        assert!(out.contains("# TYPE randomx_rpc_latency_seconds summary\n"));
+        assert!(out.contains("randomx_rpc_latency_seconds_sum{endpoint=\"eth:http://127.0.0.1\"} 1.000\n"));
+        assert!(out.contains("randomx_rpc_latency_seconds_count{endpoint=\"eth:http://127.0.0.1\"} 2\n"));
+        assert!(out.contains("randomx_current_key_block 4096\n"));
+        assert!(out.contains("randomx_allocated_units 5\n"));
+        assert!(out.contains("randomx_deallocated_units 1\n"));
+        assert!(out.contains("randomx_solutions_verified_total 1\n"));
+        assert!(out.contains("randomx_solutions_rejected_total 2\n"));
+    }
+}
@@ -1,21 +1,35 @@
 use hex;
 use fluence_keypair::{key_pair::KeyFormat, public_key::PublicKey, signature::Signature};
 
+use chrono::Utc;
+
 use crate::hashers::keccak_hasher;
+use crate::keyblock::KeyBlockSource;
 use crate::KEYPAIR as keypair;
 
+pub const SIGNATURE_LEN: usize = 64;
+
 // collection of methods for stuff that might com from chain, Nox, etc.
 
 // sign with ed25519 using fluence-keypair crate. this might be done on Nox.
-pub fn signer(msg: &Vec<u8>) -> [u8; 32] {
-    let sig = keypair.sign(msg).unwrap();
-    sig.to_vec()[..32].try_into().unwrap()
+// returns the full 64 byte signature so it can be checked with verify_sig.
+pub fn signer(msg: &Vec<u8>) -> Vec<u8> {
+    let sig = keypair.get().expect("keypair not loaded").sign(msg).unwrap();
+    // Signature::to_vec borrows the raw bytes, this is the only copy
+    Vec::from(sig.to_vec())
 }
 
+// pk is the encoded public key, i.e., PublicKey::encode
 pub fn verify_sig(pk: &Vec<u8>, msg: &Vec<u8>, signature: Vec<u8>) -> bool {
+    if pk.is_empty() || signature.len() != SIGNATURE_LEN {
+        return false;
+    }
     let sig = Signature::from_bytes(KeyFormat::Ed25519, signature);
-    let priv_key = PublicKey::decode(pk).unwrap();
-    match priv_key.verify(msg, &sig) {
+    let pub_key = match PublicKey::decode(pk) {
+        Ok(k) => k,
+        Err(_) => { return false; }
+    };
+    match pub_key.verify(msg, &sig) {
         Ok(_) => true,
         Err(_) => false,
     }
@@ -46,6 +60,34 @@ impl ThreadId {
     }
 }
 
+// local chain stand-in: starts at start_height and produces a block every block_time_secs
+pub struct MockBlockSource {
+    start_height: u64,
+    block_time_secs: u64,
+    started: i64,
+}
+
+impl MockBlockSource {
+    pub fn new(start_height: u64, block_time_secs: u64) -> Self {
+        MockBlockSource {
+            start_height,
+            block_time_secs: std::cmp::max(1, block_time_secs),
+            started: Utc::now().timestamp(),
+        }
+    }
+}
+
+impl KeyBlockSource for MockBlockSource {
+    fn name(&self) -> String {
+        format!("mock:{}+1/{}s", self.start_height, self.block_time_secs)
+    }
+
+    fn block_height(&self) -> Result<u64, String> {
+        let elapsed = std::cmp::max(0, Utc::now().timestamp() - self.started) as u64;
+        Ok(self.start_height + elapsed / self.block_time_secs)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+use crate::hashers::keccak_hasher;
+
+// deterministic RandomX nonces: H = Keccak(peer_id, thread_id, key_block, counter)
+// thread ids are unique across the pool and the counter increases monotonically per thread and key block so
+// nonces can't collide. a verifier only needs the solution's fields to recompute H and no signature is
+// needed per hash. counters are leased in blocks and each lease is persisted before use so a restart
+// resumes past anything that may have been hashed.
+
+pub const NONCE_LEASE: u64 = 1 << 16;
+
+pub fn derive(peer_id: &str, thread_id: &str, key_block: &u64, counter: &u64) -> [u8; 32] {
+    keccak_hasher(&format!("{}_{}_{}_{}", peer_id, thread_id, key_block, counter))
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+struct NonceLease {
+    key_block: u64,
+    // first counter not handed out yet
+    next_counter: u64,
+}
+
+pub struct NonceLeaser {
+    path: String,
+    leases: Mutex<HashMap<String, NonceLease>>,
+}
+
+impl NonceLeaser {
+    // a missing or unreadable state file starts all counters at zero
+    pub fn load(path: &str) -> Self {
+        let leases = match fs::read_to_string(path) {
+            Ok(s) => match serde_json::from_str(&s) {
+                Ok(l) => l,
+                Err(e) => {
+                    log::warn!("invalid nonce state {}: {}. starting from scratch.", path, e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        NonceLeaser { path: path.to_string(), leases: Mutex::new(leases) }
+    }
+
+    // write to a temp file and rename so a crash can't leave a torn state file
+    fn persist(&self, leases: &HashMap<String, NonceLease>) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", self.path);
+        let data = serde_json::to_vec(leases).map_err(|e| format!("{}", e))?;
+        fs::write(&tmp_path, data).map_err(|e| format!("{}", e))?;
+        fs::rename(&tmp_path, &self.path).map_err(|e| format!("{}", e))
+    }
+
+    // hands out the next block of counters for a thread and key block. counters restart at zero for a new key block.
+    pub fn lease(&self, thread_id: &str, key_block: u64) -> std::ops::Range<u64> {
+        let mut leases = self.leases.lock().unwrap();
+        let lease = leases.entry(thread_id.to_string()).or_insert(NonceLease { key_block, next_counter: 0 });
+        if lease.key_block != key_block {
+            *lease = NonceLease { key_block, next_counter: 0 };
+        }
+        let start = lease.next_counter;
+        lease.next_counter += NONCE_LEASE;
+        if let Err(e) = self.persist(&leases) {
+            log::error!("failed to persist nonce state to {}: {}. counters may repeat after a restart.", self.path, e);
+        }
+        start..start + NONCE_LEASE
+    }
+}
+
+pub struct NonceGenerator<'a> {
+    leaser: &'a NonceLeaser,
+    peer_id: String,
+    thread_id: String,
+    key_block: u64,
+    counters: std::ops::Range<u64>,
+}
+
+impl<'a> NonceGenerator<'a> {
+    pub fn new(leaser: &'a NonceLeaser, peer_id: &str, thread_id: &str, key_block: u64) -> Self {
+        let counters = leaser.lease(thread_id, key_block);
+        NonceGenerator {
+            leaser,
+            peer_id: peer_id.to_string(),
+            thread_id: thread_id.to_string(),
+            key_block,
+            counters,
+        }
+    }
+
+    // next counter and the RandomX input derived from it
+    pub fn next_nonce(&mut self) -> (u64, [u8; 32]) {
+        let counter = match self.counters.next() {
+            Some(c) => c,
+            None => {
+                self.counters = self.leaser.lease(&self.thread_id, self.key_block);
+                self.counters.next().unwrap()
+            }
+        };
+        (counter, derive(&self.peer_id, &self.thread_id, &self.key_block, &counter))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("randomx_nonce_{}_{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn derive_test() {
+        let h = derive("peer", "aa", &2048, &7);
+        assert_eq!(h, derive("peer", "aa", &2048, &7));
+        assert_ne!(h, derive("peer", "bb", &2048, &7));
+        assert_ne!(h, derive("peer", "aa", &4096, &7));
+        assert_ne!(h, derive("peer", "aa", &2048, &8));
+        // no ambiguity from concatenation
+        assert_ne!(derive("peer", "aa", &20, &48), derive("peer", "aa", &204, &8));
+    }
+
+    #[test]
+    fn resume_test() {
+        let path = state_path("resume");
+        let leaser = NonceLeaser::load(&path);
+        let mut gen = NonceGenerator::new(&leaser, "peer", "aa", 2048);
+        let mut last = None;
+        for _ in 0..NONCE_LEASE + 2 {
+            let (c, _) = gen.next_nonce();
+            if let Some(l) = last {
+                assert!(c > l);
+            }
+            last = Some(c);
+        }
+        assert_eq!(last, Some(NONCE_LEASE + 1));
+
+        // restart picks up after the last lease
+        let leaser = NonceLeaser::load(&path);
+        let mut gen = NonceGenerator::new(&leaser, "peer", "aa", 2048);
+        assert_eq!(gen.next_nonce().0, 2 * NONCE_LEASE);
+        // other threads and key blocks have their own counters
+        assert_eq!(NonceGenerator::new(&leaser, "peer", "bb", 2048).next_nonce().0, 0);
+        assert_eq!(NonceGenerator::new(&leaser, "peer", "aa", 4096).next_nonce().0, 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
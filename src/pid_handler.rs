@@ -1,25 +1,29 @@
-use crate::PID_PATH;
+use crate::error::Error;
+use crate::paths;
 use std::fs::File;
 use std::fs::remove_file;
 use std::fs::metadata;
 
 pub fn rm_pid() -> bool {
-    if metadata(PID_PATH).is_err() {
+    if metadata(&paths().pid).is_err() {
         return true;
     }
-    match remove_file(PID_PATH) {
+    match remove_file(&paths().pid) {
         Ok(_) => true,
         Err(_) => false,
     }
 }
 
-pub fn write_pid() -> bool {
-    let mut writer = File::create(PID_PATH).unwrap();
+pub fn write_pid() -> Result<(), Error> {
+    let mut writer = match File::create(&paths().pid) {
+        Ok(f) => f,
+        Err(e) => { return Err(Error::io(format!("can't create pid file {}", paths().pid), e)); }
+    };
 
     let pid = std::process::id();
     match serde_json::to_writer(&mut writer, &pid) {
-        Ok(_) => true,
-        Err(_) => false,
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::io(format!("can't write pid file {}", paths().pid), e.into())),
     }
 
 }
\ No newline at end of file
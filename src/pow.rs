@@ -1,34 +1,128 @@
 use chrono::Utc;
-use crossbeam::channel::Sender;
-use rust_randomx::{ Context, Hasher };
-use std::sync::{Arc, Mutex, RwLock};
+use crossbeam::channel::{Sender, TrySendError};
+use rust_randomx::{ Context, Hasher, Output };
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::sync::atomic::Ordering::Relaxed;
 
+use crate::cfg_handler::RXThreading;
+use crate::error::Error;
 use crate::hashers;
 use crate::mocks;
+use crate::nonce;
 use crate::puzzle;
-use crate::{APP_EXIT, RANDOMX_RESTART};
-
-use crate::{PEERID, PUZZLE_DIFFICULTY};
+use crate::state::{PowState, SharedState};
+use crate::PEERID;
 
 type AMVS = Arc<Mutex<Vec<String>>>;
 type ARU32= Arc<RwLock<u32>>;
+type ARVS = Arc<RwLock<Vec<String>>>;
+
+// fast mode datasets shared by the MULTI thread model, keyed by signed context, one map per pool. we only
+// hold weak refs so a dataset is released once the last hasher using it exits, e.g., after a key block change.
+pub type SharedContexts = Mutex<HashMap<Vec<u8>, Weak<Context>>>;
+
+// get the shared dataset for a key or build it. the lock is held while building so that concurrently
+// starting threads wait for the one dataset rather than each allocating 2 GB of their own.
+fn shared_context(signed_context: &Vec<u8>, fast: bool, state: &PowState) -> Arc<Context> {
+    let mut contexts = state.shared_contexts.lock().unwrap();
+    contexts.retain(|_, c| c.strong_count() > 0);
+    if let Some(context) = contexts.get(signed_context).and_then(|c| c.upgrade()) {
+        return context;
+    }
+    let context = match state.precompute.take_prepared(signed_context) {
+        Some(c) => c,
+        None => {
+            log::info!("building shared randomx dataset for key block context");
+            Arc::new(Context::new(signed_context, fast))
+        }
+    };
+    contexts.insert(signed_context.clone(), Arc::downgrade(&context));
+    context
+}
+
+// precomputed dataset if available, otherwise built on the spot
+fn randomx_context(signed_context: &Vec<u8>, thread_model: &RXThreading, fast: bool, state: &PowState) -> Arc<Context> {
+    match thread_model {
+        RXThreading::SINGLE => match state.precompute.take_prepared(signed_context) {
+            Some(c) => c,
+            None => Arc::new(Context::new(signed_context, fast)),
+        },
+        RXThreading::MULTI => shared_context(signed_context, fast, state),
+    }
+}
+
+// signed contexts for a key block, one per compute unit or one for all with the MULTI thread model
+pub fn signed_contexts(key_block: &u64, peer_id: &str, thread_names: &[String], thread_model: &RXThreading) -> Vec<Vec<u8>> {
+    let mut names: Vec<&str> = thread_names.iter().map(|n| context_name(thread_model, peer_id, n)).collect();
+    names.dedup();
+    names.iter().map(|n| mocks::signer(&context_hash(key_block, n).to_vec())).collect()
+}
+
+// K = Sign(Keccak(block_height, thread_id)) -- verifiers rebuild the hash from the solution
+pub fn context_hash(key_block: &u64, thread_name: &str) -> [u8; 32] {
+    let context_raw = format!("{}{}", key_block, thread_name);
+    hashers::keccak_hasher(&context_raw)
+}
+
+// the MULTI model shares one dataset per key block so K is tied to the peer rather than the thread:
+// K = Sign(Keccak(block_height, peer_id))
+pub fn context_name<'a>(thread_model: &RXThreading, peer_id: &'a str, thread_name: &'a str) -> &'a str {
+    match thread_model {
+        RXThreading::SINGLE => thread_name,
+        RXThreading::MULTI => peer_id,
+    }
+}
+
+// randomx pipelines hashing: hash_next starts on the next input and returns the hash of the previous one.
+// we keep the input in flight so each hash is handed out with the exact counter and input it was computed from.
+pub struct PipelinedHasher<'a> {
+    hasher: Hasher,
+    nonces: nonce::NonceGenerator<'a>,
+    in_flight: (u64, [u8; 32]),
+}
+
+impl<'a> PipelinedHasher<'a> {
+    pub fn new(context: Arc<Context>, mut nonces: nonce::NonceGenerator<'a>) -> Self {
+        let mut hasher = Hasher::new(context);
+        let in_flight = nonces.next_nonce();
+        hasher.hash_first(&in_flight.1);
+        PipelinedHasher { hasher, nonces, in_flight }
+    }
+
+    // counter, input and the hash of that input
+    pub fn next_hash(&mut self) -> (u64, [u8; 32], Output) {
+        let next = self.nonces.next_nonce();
+        let out = self.hasher.hash_next(&next.1);
+        let (counter, input) = std::mem::replace(&mut self.in_flight, next);
+        (counter, input, out)
+    }
+}
 
 pub fn randomx_fast_instance(
-    key_block: &u64,
+    state: &PowState,
+    start_key_block: &u64,
     peer_id: &str,
     sender: &Sender<puzzle::PuzzleSolution>,
+    puzzle_type: &puzzle::PuzzleType,
     puzzle_difficulty: &u32,
     alloc_threads:AMVS,
     dealloc_threads:AMVS,
-    dealloc_requests:ARU32,
+    dealloc_requests:ARVS,
     randomx_up_counter:ARU32
 ) {
-    let context_raw = format!("{}{}", key_block, &thread::current().name().unwrap());
-    let context_hash = hashers::keccak_hasher(&context_raw);
-    let signed_context = mocks::signer(&context_hash.to_vec());
-    let context = Arc::new(Context::new(&signed_context, true));
+    let thread_model = state.thread_model();
+    let fast = !state.light_datasets.load(Relaxed);
+    let current_thread = thread::current();
+    let context_name = context_name(&thread_model, peer_id, current_thread.name().unwrap());
+    let mut key_block = *start_key_block;
+    let mut signed_context = mocks::signer(&context_hash(&key_block, context_name).to_vec());
+    let thread_metrics = state.metrics.thread(current_thread.name().unwrap());
+    let init_start = std::time::Instant::now();
+    let context = randomx_context(&signed_context, &thread_model, fast, state);
+    thread_metrics.dataset_init_ms.swap(init_start.elapsed().as_millis() as u64, Relaxed);
 
     // update "up" counter
     let guard = randomx_up_counter.write();
@@ -39,98 +133,128 @@ pub fn randomx_fast_instance(
 
     log::info!("hasher setup {}", thread::current().name().unwrap());
 
-    let mut randomx_hasher = Hasher::new(context);
-    // this makes for a pretty small byte array. we could either hash the value or make it bigger, e.g., square it.
-    let nonce_raw: i64 = Utc::now().timestamp_nanos_opt().unwrap() + *key_block as i64;
-    let mut nonce = mocks::signer(&nonce_raw.to_le_bytes().to_vec());
-    randomx_hasher.hash_first(&nonce);
+    let mut difficulty = *puzzle_difficulty;
+    let mut target = puzzle_type.target(&difficulty);
+
+    let mut randomx_hasher = PipelinedHasher::new(context, nonce::NonceGenerator::new(&state.nonce_leases, peer_id, current_thread.name().unwrap(), key_block));
     loop {
-        let next_nonce_raw: i64 = Utc::now().timestamp_nanos_opt().unwrap() + *key_block as i64;
-        let next_nonce = mocks::signer(&next_nonce_raw.to_le_bytes().to_vec());
-        let out = randomx_hasher.hash_next(&next_nonce);
+        let (counter, input, out) = randomx_hasher.next_hash();
+        thread_metrics.hashes.fetch_add(1, Relaxed);
         
-        if out.leading_zeros() == *puzzle_difficulty {
-            let solution = puzzle::PuzzleSolution::new(
-                peer_id.clone().as_bytes().to_vec(),
-                key_block.clone(),
-                signed_context.to_vec(),
-                thread::current().name().unwrap().as_bytes().to_vec(),
-                nonce_raw.to_le_bytes().to_vec(),
-                nonce.to_vec(), //signed nonce
-                out.as_ref().to_vec(),
-                puzzle_difficulty.clone(),
-            );
-            sender.send(solution).unwrap();
+        if puzzle::meets_target(out.as_ref(), &target) {
+            thread_metrics.solutions.fetch_add(1, Relaxed);
+            let solution = puzzle::PuzzleSolution {
+                peer_id: peer_id.as_bytes().to_vec(),
+                key_block,
+                signed_context: signed_context.to_vec(),
+                thread_name: thread::current().name().unwrap().as_bytes().to_vec(),
+                nonce_counter: counter,
+                nonce: input.to_vec(),
+                signed_nonce: mocks::signer(&input.to_vec()), // only golden hashes get signed
+                hash: out.as_ref().to_vec(),
+                difficulty,
+                thread_model,
+                puzzle_type: *puzzle_type,
+                status: puzzle::SolutionStatus::Unverified,
+            };
+            match sender.try_send(solution) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("verifier queue is full, dropped solution of thread {}", thread::current().name().unwrap());
+                }
+                // the verifier only goes away on shutdown
+                Err(TrySendError::Disconnected(_)) => {
+                    log::warn!("verifier is gone, dropped solution of thread {}", thread::current().name().unwrap());
+                }
+            }
             // log::info!("got a match {}", thread::current().name().unwrap());
         }
-        
-        nonce = next_nonce;
+
+        // difficulty can be changed at runtime via the control socket
+        if state.puzzle_difficulty.load(Relaxed) != difficulty {
+            difficulty = state.puzzle_difficulty.load(Relaxed);
+            target = puzzle_type.target(&difficulty);
+        }
+
+        // swap in the next key block's dataset in place rather than waiting for a restart. the old hasher, and
+        // with it our dataset, goes first so a dataset that wasn't precomputed doesn't add to the memory budget.
+        if state.hot_swap.load(Relaxed) && state.current_keyblock.load(Relaxed) != key_block {
+            drop(randomx_hasher);
+            key_block = state.current_keyblock.load(Relaxed);
+            signed_context = mocks::signer(&context_hash(&key_block, context_name).to_vec());
+            let swap_start = std::time::Instant::now();
+            let nonces = nonce::NonceGenerator::new(&state.nonce_leases, peer_id, current_thread.name().unwrap(), key_block);
+            randomx_hasher = PipelinedHasher::new(randomx_context(&signed_context, &thread_model, fast, state), nonces);
+            thread_metrics.dataset_init_ms.swap(swap_start.elapsed().as_millis() as u64, Relaxed);
+            log::info!("hot swapped thread {} to key block {} in {} millis", current_thread.name().unwrap(), key_block, swap_start.elapsed().as_millis());
+        }
 
         if thread_dealloc(&alloc_threads, &dealloc_threads, &dealloc_requests, &randomx_up_counter) {
             log::info!("dealloc exit for thread {}", thread::current().name().unwrap());
             break;
         }
 
-        if RANDOMX_RESTART.load(Relaxed) {
+        if state.randomx_restart.load(Relaxed) {
             log::info!("app exit for thread {}", thread::current().name().unwrap());
             break;
         }
 
-        if APP_EXIT.load(Relaxed) {
+        if state.exiting() {
             log::info!("app exit for thread {}", thread::current().name().unwrap());
             break;
         }
     }
 }
 
-fn thread_dealloc(alloc_threads:&AMVS, dealloc_threads:&AMVS, dealloc_requests:&ARU32, randomx_up_counter: &ARU32) -> bool {
-    let mut dealloc_exit = false;
-
-    let reader = dealloc_requests.read();
-    if reader.is_ok() && *reader.unwrap() > 0 {
-        // lock counter
-        let c_lock = dealloc_requests.write();
-        if c_lock.is_ok() {
-            let mut rw_guard = c_lock.unwrap();
-            *rw_guard -= 1;
-        } // drop counter lock
-            
-        let reg_name = format!("{}", thread::current().name().unwrap());
-        
-        let mut alloc_guard = alloc_threads.lock().unwrap();
-        let v = &mut alloc_guard;
-        v.retain(|name| name != &reg_name);
-        drop(alloc_guard); //manual drop to speed things up
+// a thread only exits if its own name, i.e., compute unit id, was requested for deallocation
+fn thread_dealloc(alloc_threads:&AMVS, dealloc_threads:&AMVS, dealloc_requests:&ARVS, randomx_up_counter: &ARU32) -> bool {
+    let reg_name = format!("{}", thread::current().name().unwrap());
 
-        let mut dealloc_guard = dealloc_threads.lock().unwrap();
-        let v = &mut dealloc_guard;
-        v.push(reg_name);
-        drop(dealloc_guard);
+    let requested = match dealloc_requests.read() {
+        Ok(r) => r.iter().any(|name| name == &reg_name),
+        Err(_) => false,
+    };
+    if !requested {
+        return false;
+    }
 
-        let up_counter_guard = randomx_up_counter.write();
-        if up_counter_guard.is_ok() {
-            let mut rw_guard = up_counter_guard.unwrap();
-            *rw_guard -= 1;
-        }
-        
-        dealloc_exit = true
+    if let Ok(mut rw_guard) = dealloc_requests.write() {
+        rw_guard.retain(|name| name != &reg_name);
+    } // drop request lock
+
+    let mut alloc_guard = alloc_threads.lock().unwrap();
+    let v = &mut alloc_guard;
+    v.retain(|name| name != &reg_name);
+    drop(alloc_guard); //manual drop to speed things up
+
+    let mut dealloc_guard = dealloc_threads.lock().unwrap();
+    let v = &mut dealloc_guard;
+    v.push(reg_name);
+    drop(dealloc_guard);
+
+    let up_counter_guard = randomx_up_counter.write();
+    if up_counter_guard.is_ok() {
+        let mut rw_guard = up_counter_guard.unwrap();
+        *rw_guard -= 1;
     }
-    dealloc_exit
+    true
 }
 
+// starts one thread per compute unit id, see compute_units for where they come from
 pub fn randomx_thread_pool_handler(
-    num_threads: u32,
-    key_block: u64,
+    state: &SharedState,
+    thread_ids: Vec<&str>,
     tx: Sender<puzzle::PuzzleSolution>,
     alloc_threads:&AMVS, 
     dealloc_threads:&AMVS, 
-    dealloc_requests:&ARU32,
+    dealloc_requests:&ARVS,
     randomx_up_counter:&ARU32,
-    reg_names: Option<Vec<&str>>
-    ) -> Result<Vec<thread::JoinHandle<()>>, ()> {
+    ) -> Result<Vec<thread::JoinHandle<()>>, Error> {
     
+    let key_block = state.current_keyblock.load(Relaxed);
     let mut thread_handler:Vec<thread::JoinHandle<()>> = vec![];
-    for i in 0..num_threads {
+    for (i, thread_id) in thread_ids.iter().enumerate() {
+        let state = Arc::clone(state);
         let alloc_threads = Arc::clone(&alloc_threads);
         let dealloc_threads = Arc::clone(&dealloc_threads);
         let dealloc_requests = Arc::clone(&dealloc_requests);
@@ -138,44 +262,301 @@ pub fn randomx_thread_pool_handler(
         
         let sender = tx.clone();
 
-        let reg_name:String = match reg_names {
-            Some(ref n) => n[i as usize].to_string(),
-            None => mocks::ThreadId::new(&*PEERID, &i).to_hex(),
-        };
-
-        // let reg_name = mocks::ThreadId::new(&*PEERID, &i).to_hex();
+        let reg_name = thread_id.to_string();
         let builder = thread::Builder::new().name(reg_name.clone());
 
-        thread_handler.push(builder.spawn(move || {
+        let handle = builder.spawn(move || {
 
             // register thread .. maybe
             let mut guard = alloc_threads.lock().unwrap();
             let v = &mut guard;
             v.push(reg_name);
             drop(guard);
+            let puzzle_type = *state.puzzle_type.read().unwrap();
+            let difficulty = state.puzzle_difficulty.load(Relaxed);
             randomx_fast_instance(
+                &state,
                 &key_block, 
                 &*PEERID, 
                 &sender, 
-                &PUZZLE_DIFFICULTY.load(Relaxed),
+                &puzzle_type,
+                &difficulty,
                 alloc_threads,
                 dealloc_threads,
                 dealloc_requests,
                 randomx_up_counter,
             );
 
-        }).unwrap());
+        });
+        match handle {
+            Ok(h) => thread_handler.push(h),
+            // threads spawned so far exit with the app
+            Err(e) => { return Err(Error::Randomx(format!("can't spawn thread {}: {}", thread_id, e))); }
+        }
         log::info!("randomx thread {} is up", i);
     }
     Ok(thread_handler)
 }
 
-pub fn randomx_verifier(signed_context: &Vec<u8>, nonce: &Vec<u8>, difficulty: &u32, puzzle_hash: &Vec<u8>) -> bool {
+// owns the randomx threads and the shared allocation bookkeeping so capacity changes requested from the
+// runtime config or the control socket go through the same code path
+pub struct RandomxPool {
+    pub state: SharedState,
+    pub alloc_threads: AMVS,
+    pub dealloc_threads: AMVS,
+    pub dealloc_requests: ARVS,
+    pub randomx_up_counter: ARU32,
+    tx: Sender<puzzle::PuzzleSolution>,
+    thread_handler: Vec<thread::JoinHandle<()>>,
+}
+
+impl RandomxPool {
+    pub fn new(state: SharedState, tx: Sender<puzzle::PuzzleSolution>) -> Self {
+        RandomxPool {
+            state,
+            alloc_threads: Arc::new(Mutex::new(Vec::<String>::new())),
+            dealloc_threads: Arc::new(Mutex::new(Vec::<String>::new())),
+            dealloc_requests: Arc::new(RwLock::new(Vec::<String>::new())),
+            randomx_up_counter: Arc::new(RwLock::<u32>::new(0)),
+            tx,
+            thread_handler: vec![],
+        }
+    }
+
+    pub fn alloc(&self) -> Vec<String> {
+        self.alloc_threads.lock().unwrap().clone()
+    }
+
+    pub fn dealloced(&self) -> Vec<String> {
+        self.dealloc_threads.lock().unwrap().clone()
+    }
+
+    pub fn up_count(&self) -> u32 {
+        *self.randomx_up_counter.read().unwrap()
+    }
+
+    pub fn start(&mut self, thread_ids: Vec<&str>) -> Result<(), Error> {
+        let handles = randomx_thread_pool_handler(
+            &self.state,
+            thread_ids,
+            self.tx.clone(),
+            &self.alloc_threads,
+            &self.dealloc_threads,
+            &self.dealloc_requests,
+            &self.randomx_up_counter,
+        )?;
+        self.thread_handler.extend(handles);
+        Ok(())
+    }
+
+    // blocks until expected randomx disks are initiated
+    pub fn wait_up(&self, expected: u32) {
+        loop {
+            match self.randomx_up_counter.read() {
+                Ok(r) => {
+                    if *r >= expected {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                },
+                Err(_) => {thread::sleep(Duration::from_millis(2_000));}
+            }
+        }
+    }
+
+    // asks the named running threads to exit and waits up to timeout for them to acknowledge by moving
+    // to the dealloc list. returns the stopped compute unit ids.
+    pub fn dealloc_named(&self, thread_ids: &[String], timeout: Duration) -> Result<Vec<String>, String> {
+        let thread_ids: Vec<String> = thread_ids.iter().map(|t| t.to_lowercase()).collect();
+        let alloc = self.alloc();
+        let unknown: Vec<&String> = thread_ids.iter().filter(|t| !alloc.contains(t)).collect();
+        if !unknown.is_empty() {
+            return Err(format!("can't deallocate threads that are not allocated: {:?}", unknown));
+        }
+        if thread_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        self.dealloc_requests.write().unwrap().extend(thread_ids.iter().cloned());
+        log::info!("need to deallocate threads {:?}", thread_ids);
+
+        let deadline = Utc::now().timestamp_millis() + timeout.as_millis() as i64;
+        loop {
+            let dealloc = self.dealloced();
+            if thread_ids.iter().all(|t| dealloc.contains(t)) {
+                break;
+            }
+            if Utc::now().timestamp_millis() > deadline {
+                // withdraw what's still pending so a late thread doesn't exit unexpectedly
+                self.dealloc_requests.write().unwrap().retain(|t| !thread_ids.contains(t));
+                let pending: Vec<&String> = thread_ids.iter().filter(|t| !dealloc.contains(t)).collect();
+                return Err(format!("dealloc of threads {:?} not acknowledged within {} millis", pending, timeout.as_millis()));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        log::info!("update allocated thread count: {} and deallocated thread count: {}", self.alloc().len(), self.dealloced().len());
+        Ok(thread_ids)
+    }
+
+    // deallocates the n most recently allocated threads
+    pub fn dealloc(&self, n: u32, timeout: Duration) -> Result<Vec<String>, String> {
+        let alloc = self.alloc();
+        if n as usize > alloc.len() {
+            return Err(format!("can't deallocate {} threads with only {} allocated", n, alloc.len()));
+        }
+        let thread_ids = alloc[alloc.len() - n as usize..].to_vec();
+        self.dealloc_named(&thread_ids, timeout)
+    }
+
+    fn spawn_units(&mut self, thread_ids: &[String]) -> Result<(), String> {
+        // new threads register themselves once running so count them upfront
+        let expected = self.alloc().len() + thread_ids.len();
+        self.start(thread_ids.iter().map(|s| s.as_str()).collect()).map_err(|e| e.to_string())?;
 
+        log::info!("waiting for additional randomx disks to be initiated. This takes a while.");
+        self.wait_up(expected as u32);
+        log::info!("A total of {} randomx disks are initiated.", self.up_count());
+        Ok(())
+    }
+
+    // registers compute units as deallocated without starting them, e.g., the ones a pool isn't to start
+    // with. they can be reallocated like any other.
+    pub fn add_deallocated(&self, thread_ids: &[String]) {
+        self.dealloc_threads.lock().unwrap().extend(thread_ids.iter().cloned());
+    }
+
+    // starts compute units new to the pool, e.g., once they are assigned on chain
+    pub fn add_units(&mut self, thread_ids: &[String]) -> Result<Vec<String>, String> {
+        let (alloc, dealloc) = (self.alloc(), self.dealloced());
+        let known: Vec<&String> = thread_ids.iter().filter(|t| alloc.contains(t) || dealloc.contains(t)).collect();
+        if !known.is_empty() {
+            return Err(format!("compute units are already in the pool: {:?}", known));
+        }
+        if thread_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        log::info!("need to add threads {:?}", thread_ids);
+        self.spawn_units(thread_ids)?;
+        Ok(thread_ids.to_vec())
+    }
+
+    // stops and forgets compute units, allocated or not, so they can't be reallocated
+    pub fn remove_units(&mut self, thread_ids: &[String], timeout: Duration) -> Result<Vec<String>, String> {
+        let alloc = self.alloc();
+        let running: Vec<String> = thread_ids.iter().filter(|t| alloc.contains(t)).cloned().collect();
+        self.dealloc_named(&running, timeout)?;
+        self.dealloc_threads.lock().unwrap().retain(|t| !thread_ids.contains(t));
+        Ok(thread_ids.to_vec())
+    }
+
+    // restarts exactly the named deallocated threads. returns the started compute unit ids.
+    pub fn realloc_named(&mut self, thread_ids: &[String]) -> Result<Vec<String>, String> {
+        let thread_ids: Vec<String> = thread_ids.iter().map(|t| t.to_lowercase()).collect();
+        let dealloc = self.dealloced();
+        let unknown: Vec<&String> = thread_ids.iter().filter(|t| !dealloc.contains(t)).collect();
+        if !unknown.is_empty() {
+            return Err(format!("can't reallocate threads that are not deallocated: {:?}", unknown));
+        }
+        if thread_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        log::info!("need to reallocate threads {:?}", thread_ids);
+        self.spawn_units(&thread_ids)?;
+
+        // clean up dealloc references
+        let mut dealloc_guard = self.dealloc_threads.lock().unwrap();
+        dealloc_guard.retain(|name| !thread_ids.contains(name));
+        Ok(thread_ids)
+    }
+
+    // reallocates the n longest deallocated threads.
+    // note we can't realloc more than we deallocated since n_alloc + n_dealloc === MAX_THREADS
+    pub fn realloc(&mut self, n: u32) -> Result<Vec<String>, String> {
+        let dealloc = self.dealloced();
+        if n as usize > dealloc.len() {
+            return Err(format!("can't reallocate {} threads with only {} deallocated", n, dealloc.len()));
+        }
+        self.realloc_named(&dealloc[0..n as usize])
+    }
+
+    // balances the pool to the requested number of deallocated threads
+    pub fn set_dealloc_target(&mut self, target: u32, max_threads: u32, timeout: Duration) -> Result<Vec<String>, String> {
+        if target > max_threads {
+            return Err(format!("invalid thread reduction request for minus {} threads of {}", target, max_threads));
+        }
+        let dealloc_count = self.dealloc_threads.lock().unwrap().len() as u32;
+        if target > dealloc_count {
+            self.dealloc(target - dealloc_count, timeout)
+        } else if target < dealloc_count {
+            self.realloc(dealloc_count - target)
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    // tears down all threads and starts the allocated ones with the current key block. every thread has
+    // to acknowledge the restart by exiting before the flag is cleared, otherwise it would keep on hashing
+    // with the old key block or the new ones would exit right away.
+    pub fn restart(&mut self) {
+        let thread_ids = self.alloc();
+        self.state.randomx_restart.swap(true, Relaxed);
+        for t in self.thread_handler.drain(..) {
+            if t.join().is_err() {
+                log::error!("randomx thread panicked before restart");
+            }
+        }
+        self.state.randomx_restart.swap(false, Relaxed);
+
+        // threads register themselves again once running, under the same compute unit ids
+        self.alloc_threads.lock().unwrap().clear();
+        if let Ok(mut rw_guard) = self.randomx_up_counter.write() {
+            *rw_guard = 0;
+        }
+
+        let reg_names: Vec<&str> = thread_ids.iter().map(|s| s.as_str()).collect();
+        if let Err(e) = self.start(reg_names) {
+            log::error!("randomx threads not restarted: {}. {}", e, e.hint());
+            return;
+        }
+        log::info!("waiting for randomx disk to be initiated for each instance. This takes a while.");
+        self.wait_up(thread_ids.len() as u32);
+        log::info!("{} randomx disks are initiated.", self.up_count());
+    }
+
+    // waits for the threads to exit, e.g., after app exit, until the deadline. returns the ids of the
+    // threads that exited and of those that didn't. the latter are left running detached.
+    pub fn join_until(self, deadline: Instant) -> (Vec<String>, Vec<String>) {
+        let mut pending = self.thread_handler;
+        let mut stopped = vec![];
+        loop {
+            let (finished, running): (Vec<_>, Vec<_>) = pending.into_iter().partition(|t| t.is_finished());
+            for t in finished {
+                let name = t.thread().name().unwrap_or("").to_string();
+                if t.join().is_err() {
+                    log::error!("randomx thread {} panicked", name);
+                }
+                stopped.push(name);
+            }
+            pending = running;
+            if pending.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let unresponsive = pending.iter().map(|t| t.thread().name().unwrap_or("").to_string()).collect();
+        (stopped, unresponsive)
+    }
+}
+
+pub fn randomx_verifier(signed_context: &Vec<u8>, nonce: &Vec<u8>, puzzle_type: &puzzle::PuzzleType, difficulty: &u32, puzzle_hash: &Vec<u8>) -> bool {
     let context = Arc::new(Context::new(signed_context, false));
+    randomx_verify_with(context, nonce, puzzle_type, difficulty, puzzle_hash)
+}
+
+// same as randomx_verifier with a caller provided, e.g., cached, light mode context for K
+pub fn randomx_verify_with(context: Arc<Context>, nonce: &Vec<u8>, puzzle_type: &puzzle::PuzzleType, difficulty: &u32, puzzle_hash: &Vec<u8>) -> bool {
     let hasher = Hasher::new(context); // new machine based on K
     let out = hasher.hash(&nonce); // we only need the first program which we init with the nonce
-    let valid = out.leading_zeros() == *difficulty; // check if the fast hash meets the difficulty
+    let valid = puzzle::meets_target(out.as_ref(), &puzzle_type.target(difficulty)); // check if the fast hash meets the difficulty
     let out_bytes = out.as_ref().to_vec();
 
     let verified = out_bytes == *puzzle_hash;
@@ -183,4 +564,193 @@ pub fn randomx_verifier(signed_context: &Vec<u8>, nonce: &Vec<u8>, difficulty: &
         return true;
     }
     false
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn named_dealloc_test() {
+        let alloc_threads: AMVS = Arc::new(Mutex::new(vec!["aa".to_string(), "bb".to_string()]));
+        let dealloc_threads: AMVS = Arc::new(Mutex::new(vec![]));
+        let dealloc_requests: ARVS = Arc::new(RwLock::new(vec!["bb".to_string()]));
+        let randomx_up_counter: ARU32 = Arc::new(RwLock::new(2));
+
+        let run_as = |name: &str| {
+            let (a, d, r, u) = (alloc_threads.clone(), dealloc_threads.clone(), dealloc_requests.clone(), randomx_up_counter.clone());
+            thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || thread_dealloc(&a, &d, &r, &u))
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+
+        // only the named thread exits
+        assert!(!run_as("aa"));
+        assert!(run_as("bb"));
+        assert!(!run_as("bb"));
+
+        assert_eq!(*alloc_threads.lock().unwrap(), vec!["aa".to_string()]);
+        assert_eq!(*dealloc_threads.lock().unwrap(), vec!["bb".to_string()]);
+        assert!(dealloc_requests.read().unwrap().is_empty());
+        assert_eq!(*randomx_up_counter.read().unwrap(), 1);
+    }
+
+    // regression: solutions used to pair a hash with the wrong nonce
+    #[test]
+    fn pipelined_solution_test() {
+        let path = std::env::temp_dir().join(format!("randomx_pipeline_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let leaser = nonce::NonceLeaser::load(path);
+        let signed_context = b"pipelined solution test context".to_vec();
+        let context = Arc::new(Context::new(&signed_context, false));
+        let mut hasher = PipelinedHasher::new(context, nonce::NonceGenerator::new(&leaser, "peer", "aa", 2048));
+
+        let puzzle_type = puzzle::PuzzleType::ZEROS;
+        let difficulty = 1;
+        let target = puzzle_type.target(&difficulty);
+        let mut solutions = 0;
+        for expected_counter in 0..32 {
+            let (counter, input, out) = hasher.next_hash();
+            assert_eq!(counter, expected_counter);
+            assert_eq!(input, nonce::derive("peer", "aa", &2048, &counter));
+            if puzzle::meets_target(out.as_ref(), &target) {
+                solutions += 1;
+                assert!(randomx_verifier(&signed_context, &input.to_vec(), &puzzle_type, &difficulty, &out.as_ref().to_vec()));
+            }
+        }
+        assert!(solutions > 0);
+        std::fs::remove_file(path).unwrap();
+
+        // and solutions from the pool's hashing threads verify
+        let kp = crate::key_handler::decode_keypair("2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3").unwrap();
+        let _ = crate::KEYPAIR.set(Arc::new(kp));
+        let path = std::env::temp_dir().join(format!("randomx_pipeline_pool_{}.json", std::process::id()));
+        let state = Arc::new(PowState::new(path.to_str().unwrap()));
+        state.light_datasets.swap(true, Relaxed);
+        state.current_keyblock.swap(2048, Relaxed);
+        state.puzzle_difficulty.swap(difficulty, Relaxed);
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let mut pool = RandomxPool::new(Arc::clone(&state), tx);
+        pool.start(vec!["jj"]).unwrap();
+        let solution: puzzle::PuzzleSolution = rx.recv_timeout(Duration::from_secs(60)).unwrap();
+        state.app_exit.swap(true, Relaxed);
+        assert!(randomx_verifier(&solution.signed_context, &solution.nonce, &solution.puzzle_type, &solution.difficulty, &solution.hash));
+        assert_eq!(solution.verify(), Ok(()));
+        assert!(pool.join_until(Instant::now() + Duration::from_secs(30)).1.is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    // two pools with their own state run realloc sequences side by side without seeing each other's threads
+    #[test]
+    fn concurrent_realloc_test() {
+        let kp = crate::key_handler::decode_keypair("2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3").unwrap();
+        let _ = crate::KEYPAIR.set(Arc::new(kp));
+
+        let run = |names: [&'static str; 2]| {
+            thread::spawn(move || {
+                let path = std::env::temp_dir().join(format!("randomx_pool_nonces_{}_{}", std::process::id(), names[0]));
+                let state = Arc::new(PowState::new(path.to_str().unwrap()));
+                state.light_datasets.swap(true, Relaxed);
+                state.current_keyblock.swap(2048, Relaxed);
+                let (tx, _rx) = crossbeam::channel::unbounded();
+                let mut pool = RandomxPool::new(Arc::clone(&state), tx);
+                pool.start(names.to_vec()).unwrap();
+                pool.wait_up(2);
+
+                let sorted = |mut v: Vec<String>| { v.sort(); v };
+                assert_eq!(sorted(pool.alloc()), vec![names[0].to_string(), names[1].to_string()]);
+                assert_eq!(pool.dealloc_named(&[names[1].to_string()], Duration::from_secs(30)).unwrap(), vec![names[1].to_string()]);
+                assert_eq!(pool.alloc(), vec![names[0].to_string()]);
+                assert_eq!(pool.dealloced(), vec![names[1].to_string()]);
+                assert!(pool.realloc(2).is_err());
+                assert_eq!(pool.realloc(1).unwrap(), vec![names[1].to_string()]);
+                assert_eq!(sorted(pool.alloc()), vec![names[0].to_string(), names[1].to_string()]);
+                assert!(pool.dealloced().is_empty());
+                assert_eq!(pool.up_count(), 2);
+
+                state.app_exit.swap(true, Relaxed);
+                let (stopped, unresponsive) = pool.join_until(Instant::now() + Duration::from_secs(30));
+                assert_eq!(stopped.len(), 3);
+                assert!(unresponsive.is_empty());
+                let _ = std::fs::remove_file(path);
+            })
+        };
+        let a = run(["aa", "bb"]);
+        let b = run(["cc", "dd"]);
+        a.join().unwrap();
+        b.join().unwrap();
+    }
+
+    // restarted threads used to see the restart flag still set and exit right away
+    #[test]
+    fn restart_test() {
+        let kp = crate::key_handler::decode_keypair("2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3").unwrap();
+        let _ = crate::KEYPAIR.set(Arc::new(kp));
+
+        let path = std::env::temp_dir().join(format!("randomx_restart_nonces_{}", std::process::id()));
+        let state = Arc::new(PowState::new(path.to_str().unwrap()));
+        state.light_datasets.swap(true, Relaxed);
+        state.current_keyblock.swap(2048, Relaxed);
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let mut pool = RandomxPool::new(Arc::clone(&state), tx);
+        pool.start(vec!["ee", "ff"]).unwrap();
+        pool.wait_up(2);
+        pool.dealloc_named(&["ff".to_string()], Duration::from_secs(30)).unwrap();
+
+        state.current_keyblock.swap(4096, Relaxed);
+        pool.restart();
+        assert!(!state.randomx_restart.load(Relaxed));
+        assert_eq!(pool.alloc(), vec!["ee".to_string()]);
+        assert_eq!(pool.dealloced(), vec!["ff".to_string()]);
+        assert_eq!(pool.up_count(), 1);
+        thread::sleep(Duration::from_millis(200));
+        assert!(pool.thread_handler.iter().all(|t| !t.is_finished()));
+
+        // deallocated threads can still come back after a restart
+        assert_eq!(pool.realloc(1).unwrap(), vec!["ff".to_string()]);
+        assert_eq!(pool.up_count(), 2);
+
+        state.app_exit.swap(true, Relaxed);
+        let (mut stopped, unresponsive) = pool.join_until(Instant::now() + Duration::from_secs(30));
+        stopped.sort();
+        assert_eq!(stopped, vec!["ee".to_string(), "ff".to_string()]);
+        assert!(unresponsive.is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    // units assigned and unassigned on chain are started and forgotten
+    #[test]
+    fn add_remove_units_test() {
+        let kp = crate::key_handler::decode_keypair("2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3").unwrap();
+        let _ = crate::KEYPAIR.set(Arc::new(kp));
+
+        let path = std::env::temp_dir().join(format!("randomx_units_nonces_{}", std::process::id()));
+        let state = Arc::new(PowState::new(path.to_str().unwrap()));
+        state.light_datasets.swap(true, Relaxed);
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let mut pool = RandomxPool::new(Arc::clone(&state), tx);
+        pool.start(vec!["gg", "hh"]).unwrap();
+        pool.wait_up(2);
+        pool.dealloc_named(&["hh".to_string()], Duration::from_secs(30)).unwrap();
+
+        assert!(pool.add_units(&["hh".to_string()]).is_err());
+        assert_eq!(pool.add_units(&["ii".to_string()]).unwrap(), vec!["ii".to_string()]);
+        assert_eq!(pool.up_count(), 2);
+        assert_eq!(pool.remove_units(&["gg".to_string(), "hh".to_string()], Duration::from_secs(30)).unwrap().len(), 2);
+        assert_eq!(pool.alloc(), vec!["ii".to_string()]);
+        assert!(pool.dealloced().is_empty());
+        assert_eq!(pool.up_count(), 1);
+
+        // units that never ran, e.g., deallocated from the start, can be reallocated
+        pool.add_deallocated(&["kk".to_string()]);
+        assert_eq!(pool.dealloced(), vec!["kk".to_string()]);
+        assert_eq!(pool.realloc_named(&["kk".to_string()]).unwrap(), vec!["kk".to_string()]);
+        assert_eq!(pool.up_count(), 2);
+
+        state.app_exit.swap(true, Relaxed);
+        assert!(pool.join_until(Instant::now() + Duration::from_secs(30)).1.is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+}
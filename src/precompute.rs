@@ -0,0 +1,176 @@
+use rust_randomx::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::check_handler::CACHE_MB;
+use crate::keyblock::KeyBlockSchedule;
+use crate::state::SharedState;
+
+// builds the next key block's randomx datasets in the background so hashers can hot swap at the key block
+// boundary instead of losing 20s+ per compute unit to dataset initialization.
+
+// fast mode dataset plus slack, in MB
+pub const DATASET_MB: u64 = 2_080;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct PrecomputeCfg {
+    pub enabled: bool,
+    // start building this many blocks ahead of the next key block, at most a key block interval
+    pub lead_blocks: u64,
+    // cap for all randomx datasets, current and prepared. defaults to what the os reports available.
+    pub memory_budget_mb: Option<u64>,
+}
+
+impl Default for PrecomputeCfg {
+    fn default() -> Self {
+        PrecomputeCfg {
+            enabled: false,
+            lead_blocks: 256,
+            memory_budget_mb: None,
+        }
+    }
+}
+
+// prepared contexts keyed by signed context, tagged with their key block
+type PreparedContexts = HashMap<Vec<u8>, (u64, Arc<Context>)>;
+
+// precompute runs and their datasets, part of a pool's state
+#[derive(Default)]
+pub struct Precomputed {
+    contexts: Mutex<PreparedContexts>,
+    running: AtomicBool,
+    last_key_block: AtomicU64,
+}
+
+impl Precomputed {
+    pub fn take_prepared(&self, signed_context: &Vec<u8>) -> Option<Arc<Context>> {
+        self.contexts.lock().unwrap().remove(signed_context).map(|(_, c)| c)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Relaxed)
+    }
+
+    // key block the last precompute run was started for
+    pub fn last_key_block(&self) -> u64 {
+        self.last_key_block.load(Relaxed)
+    }
+
+    fn discard_except(&self, key_block: u64) {
+        let mut prepared = self.contexts.lock().unwrap();
+        let before = prepared.len();
+        prepared.retain(|_, (kb, _)| *kb == key_block);
+        if before > prepared.len() {
+            log::info!("discarded {} unused precomputed randomx datasets.", before - prepared.len());
+        }
+    }
+}
+
+fn available_mb() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb / 1_024)
+}
+
+// light mode only needs the cache
+pub fn dataset_mb(fast: bool) -> u64 {
+    match fast {
+        true => DATASET_MB,
+        false => CACHE_MB,
+    }
+}
+
+// number of additional datasets we can afford with in_use datasets already allocated
+pub fn datasets_that_fit(cfg: &PrecomputeCfg, in_use: u64, fast: bool) -> u64 {
+    let size = dataset_mb(fast);
+    match cfg.memory_budget_mb {
+        Some(budget) => (budget / size).saturating_sub(in_use),
+        // keep one dataset worth of headroom for everything else
+        None => match available_mb() {
+            Some(mb) => (mb / size).saturating_sub(1),
+            None => 0,
+        },
+    }
+}
+
+// block height to start preparing next_key_block's datasets at. the lead is capped to the key block
+// interval, a longer one would have us prepare for a key block before the current one is reached.
+pub fn precompute_from(next_key_block: u64, cfg: &PrecomputeCfg, schedule: &KeyBlockSchedule) -> u64 {
+    next_key_block.saturating_sub(std::cmp::min(cfg.lead_blocks, schedule.offset))
+}
+
+// builds the datasets one at a time to limit the impact on the hashing threads
+pub fn spawn_precompute(state: SharedState, key_block: u64, signed_contexts: Vec<Vec<u8>>, cfg: &PrecomputeCfg, in_use: u64) -> Option<thread::JoinHandle<()>> {
+    let prepared = &state.precompute;
+    if prepared.running.swap(true, Relaxed) {
+        return None;
+    }
+    prepared.last_key_block.swap(key_block, Relaxed);
+    prepared.discard_except(key_block);
+
+    // same mode as the hashers so they can hot swap to them
+    let fast = !state.light_datasets.load(Relaxed);
+    let fits = datasets_that_fit(cfg, in_use, fast) as usize;
+    let mut signed_contexts = signed_contexts;
+    if fits < signed_contexts.len() {
+        log::warn!(
+            "memory budget only allows precomputing {} of {} randomx datasets for key block {}. the rest is built at the boundary.",
+            fits, signed_contexts.len(), key_block
+        );
+        signed_contexts.truncate(fits);
+    }
+
+    let thread_state = Arc::clone(&state);
+    let handle = thread::Builder::new().name("precompute".to_string()).spawn(move || {
+        log::info!("precomputing {} randomx datasets for key block {}.", signed_contexts.len(), key_block);
+        let started = Instant::now();
+        for signed_context in signed_contexts {
+            if thread_state.exiting() {
+                break;
+            }
+            let context = Arc::new(Context::new(&signed_context, fast));
+            thread_state.precompute.contexts.lock().unwrap().insert(signed_context, (key_block, context));
+        }
+        log::info!("precomputed randomx datasets for key block {} in {}s.", key_block, started.elapsed().as_secs());
+        thread_state.precompute.running.swap(false, Relaxed);
+    });
+    match handle {
+        Ok(h) => Some(h),
+        Err(e) => {
+            log::error!("can't spawn precompute thread: {}", e);
+            state.precompute.running.swap(false, Relaxed);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_budget_test() {
+        let cfg = PrecomputeCfg { enabled: true, memory_budget_mb: Some(6 * DATASET_MB + 100), ..PrecomputeCfg::default() };
+        assert_eq!(datasets_that_fit(&cfg, 4, true), 2);
+        assert_eq!(datasets_that_fit(&cfg, 6, true), 0);
+        assert_eq!(datasets_that_fit(&cfg, 8, true), 0);
+        // light mode caches are way smaller
+        assert_eq!(datasets_that_fit(&cfg, 6, false), (6 * DATASET_MB + 100) / CACHE_MB - 6);
+    }
+
+    #[test]
+    fn lead_test() {
+        let cfg = PrecomputeCfg::default();
+        assert_eq!(precompute_from(4096, &cfg, &KeyBlockSchedule::default()), 4096 - 256);
+        // devnet schedule, the lead is capped to the interval
+        let devnet = KeyBlockSchedule { offset: 64, delay: 64 };
+        assert_eq!(precompute_from(4096, &cfg, &devnet), 4096 - 64);
+    }
+}
@@ -1,46 +1,141 @@
+use rust_randomx::Context;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use serde_json;
-use hex;
-use crate::PUZZLE_SOLUTION_DIR as DIR;
+use std::sync::Arc;
+use crate::cfg_handler::RXThreading;
+use crate::key_handler;
+use crate::mocks;
+use crate::nonce;
+use crate::pow;
+use crate::paths;
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+// ZEROS: hash needs at least difficulty leading zero bits
+// COMP: hash as 256-bit big-endian integer needs to be <= (2^256 - 1) / difficulty, i.e., difficulty is
+//       the expected number of hashes per solution and doesn't need to be a power of two
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum PuzzleType {
+    #[default]
     ZEROS,
-    // COMP,
+    COMP,
 }
+
+impl PuzzleType {
+    pub fn valid_difficulty(&self, difficulty: &u32) -> bool {
+        match self {
+            PuzzleType::ZEROS => *difficulty >= 1 && *difficulty <= 256,
+            PuzzleType::COMP => *difficulty >= 1,
+        }
+    }
+
+    // both puzzles boil down to hash <= target with the target as 256-bit big-endian integer
+    pub fn target(&self, difficulty: &u32) -> [u8; 32] {
+        match self {
+            PuzzleType::ZEROS => {
+                let zeros = std::cmp::min(*difficulty, 256) as usize;
+                let mut target = [0xffu8; 32];
+                for byte in target.iter_mut().take(zeros / 8) {
+                    *byte = 0;
+                }
+                if zeros < 256 {
+                    target[zeros / 8] = 0xff >> (zeros % 8);
+                }
+                target
+            }
+            PuzzleType::COMP => {
+                // long division of 2^256 - 1 by difficulty, one byte at a time
+                let divisor = std::cmp::max(1, *difficulty) as u64;
+                let mut target = [0u8; 32];
+                let mut rem = 0u64;
+                for byte in target.iter_mut() {
+                    let cur = (rem << 8) | 0xff;
+                    *byte = (cur / divisor) as u8;
+                    rem = cur % divisor;
+                }
+                target
+            }
+        }
+    }
+}
+
+// big-endian byte arrays of the same length compare like the integers they represent
+pub fn meets_target(hash: &[u8], target: &[u8; 32]) -> bool {
+    hash.len() == target.len() && hash <= &target[..]
+}
+// set by the verifier worker. only verified solutions are persisted.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SolutionStatus {
+    #[default]
+    Unverified,
+    Verified,
+    Rejected(String),
+}
+
+// built as a struct literal, new solutions start out Unverified
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PuzzleSolution {
     pub peer_id: Vec<u8>,
     pub key_block: u64,
     pub signed_context: Vec<u8>,
     pub thread_name: Vec<u8>,
+    // H = Keccak(peer_id, thread_name, key_block, nonce_counter), the RandomX input. older solution files
+    // don't have it and are rejected by verify rather than failing to parse.
+    #[serde(default)]
+    pub nonce_counter: u64,
     pub nonce: Vec<u8>,
     pub signed_nonce: Vec<u8>,
     pub hash: Vec<u8>,
     pub difficulty: u32,
+    #[serde(default)]
+    pub thread_model: RXThreading,
+    #[serde(default)]
+    pub puzzle_type: PuzzleType,
+    #[serde(default)]
+    pub status: SolutionStatus,
 }
 
 impl PuzzleSolution {
-    pub fn new(peer_id: Vec<u8>, key_block: u64, signed_context:Vec<u8>, thread_name:Vec<u8>, nonce:Vec<u8>, signed_nonce:Vec<u8>, hash:Vec<u8>, difficulty: u32) -> Self {
-        PuzzleSolution {
-            peer_id,
-            key_block,
-            signed_context,
-            thread_name,
-            nonce,
-            signed_nonce,
-            hash,
-            // puzzle_type            // need to add if that's going to be dynamic
-            difficulty      // need to add if that's going to be dynamic
+    // checks context signature, nonce signature and randomx hash against the peer's public key.
+    // note that this runs randomx in light mode, which is slow, and should be kept off the hot threads.
+    pub fn verify(&self) -> Result<(), String> {
+        self.verify_with(|signed_context| Arc::new(Context::new(signed_context, false)))
+    }
+
+    // same as verify but the light mode context for K comes from the caller, e.g., a cache.
+    // it's only requested once the signatures check out.
+    pub fn verify_with<F: FnOnce(&Vec<u8>) -> Arc<Context>>(&self, light_context: F) -> Result<(), String> {
+        let peer_id = match String::from_utf8(self.peer_id.clone()) {
+            Ok(p) => p,
+            Err(_) => { return Err("peer id is not valid utf8".to_string()); }
+        };
+        let thread_name = match String::from_utf8(self.thread_name.clone()) {
+            Ok(t) => t,
+            Err(_) => { return Err("thread name is not valid utf8".to_string()); }
+        };
+        let pk = key_handler::public_key_from_peer_id(&peer_id)?.encode();
+
+        let context_name = pow::context_name(&self.thread_model, &peer_id, &thread_name);
+        let context_hash = pow::context_hash(&self.key_block, context_name);
+        if !mocks::verify_sig(&pk, &context_hash.to_vec(), self.signed_context.clone()) {
+            return Err("invalid context signature".to_string());
+        }
+        if nonce::derive(&peer_id, &thread_name, &self.key_block, &self.nonce_counter).to_vec() != self.nonce {
+            return Err("nonce does not match peer, thread, key block and counter".to_string());
+        }
+        if !mocks::verify_sig(&pk, &self.nonce, self.signed_nonce.clone()) {
+            return Err("invalid nonce signature".to_string());
         }
+        if !pow::randomx_verify_with(light_context(&self.signed_context), &self.nonce, &self.puzzle_type, &self.difficulty, &self.hash) {
+            return Err("hash does not match nonce or difficulty".to_string());
+        }
+        Ok(())
     }
 
     pub fn to_file(&self, out_dir: Option<&str>) -> Result<(), String> {
         let out = match out_dir {
             Some(d) => d,
-            None => DIR,
+            None => paths().solution_dir.as_str(),
         };
 
         let fname = hex::encode(self.hash.clone());
@@ -62,4 +157,105 @@ impl PuzzleSolution {
         }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn signed_solution() -> PuzzleSolution {
+        let kp = crate::key_handler::decode_keypair("2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3").unwrap();
+        let _ = crate::KEYPAIR.set(Arc::new(kp));
+
+        let key_block = 2048u64;
+        let thread_name = mocks::ThreadId::new(&crate::PEERID, &0).to_hex();
+        let signed_context = mocks::signer(&pow::context_hash(&key_block, &thread_name).to_vec());
+        let nonce = nonce::derive(&crate::PEERID, &thread_name, &key_block, &42).to_vec();
+        let signed_nonce = mocks::signer(&nonce);
+        PuzzleSolution {
+            peer_id: crate::PEERID.as_bytes().to_vec(),
+            key_block,
+            signed_context,
+            thread_name: thread_name.as_bytes().to_vec(),
+            nonce_counter: 42,
+            nonce,
+            signed_nonce,
+            hash: vec![0u8; 32],
+            difficulty: 0,
+            thread_model: RXThreading::SINGLE,
+            puzzle_type: PuzzleType::ZEROS,
+            status: SolutionStatus::Unverified,
+        }
+    }
+
+    #[test]
+    fn forged_solution_test() {
+        let solution = signed_solution();
+        assert_eq!(solution.signed_context.len(), mocks::SIGNATURE_LEN);
+        assert_eq!(solution.signed_nonce.len(), mocks::SIGNATURE_LEN);
+
+        let mut forged = solution.clone();
+        forged.key_block += 1;
+        assert_eq!(forged.verify(), Err("invalid context signature".to_string()));
+
+        let mut forged = solution.clone();
+        forged.nonce_counter = 43;
+        assert_eq!(forged.verify(), Err("nonce does not match peer, thread, key block and counter".to_string()));
+
+        let mut forged = solution.clone();
+        forged.signed_nonce = mocks::signer(&vec![0u8; 32]);
+        assert_eq!(forged.verify(), Err("invalid nonce signature".to_string()));
+
+        let mut forged = solution.clone();
+        forged.peer_id = crate::KeyPair::generate_ed25519().get_peer_id().to_base58().as_bytes().to_vec();
+        assert_eq!(forged.verify(), Err("invalid context signature".to_string()));
+
+        // signatures check out but the hash was never computed
+        assert_eq!(solution.verify(), Err("hash does not match nonce or difficulty".to_string()));
+
+        // files written before nonce counters still parse
+        let mut legacy = serde_json::to_value(&solution).unwrap();
+        legacy.as_object_mut().unwrap().remove("nonce_counter");
+        let legacy: PuzzleSolution = serde_json::from_value(legacy).unwrap();
+        assert_eq!(legacy.nonce_counter, 0);
+        assert_eq!(legacy.verify(), Err("nonce does not match peer, thread, key block and counter".to_string()));
+    }
+
+    #[test]
+    fn zeros_target_test() {
+        let target = PuzzleType::ZEROS.target(&12);
+        assert_eq!(target[..2], [0x00, 0x0f]);
+        assert!(target[2..].iter().all(|b| *b == 0xff));
+
+        let mut hash = [0xffu8; 32];
+        hash[0] = 0;
+        hash[1] = 0x0f;
+        assert!(meets_target(&hash, &target));
+        // harder hashes are solutions, too
+        hash[1] = 0;
+        assert!(meets_target(&hash, &target));
+        hash[1] = 0x10;
+        assert!(!meets_target(&hash, &target));
+
+        assert_eq!(PuzzleType::ZEROS.target(&0), [0xff; 32]);
+        assert_eq!(PuzzleType::ZEROS.target(&256), [0x00; 32]);
+    }
+
+    #[test]
+    fn comp_target_test() {
+        assert_eq!(PuzzleType::COMP.target(&1), [0xff; 32]);
+        // power of two difficulty matches the leading zeros target
+        assert_eq!(PuzzleType::COMP.target(&4096), PuzzleType::ZEROS.target(&12));
+
+        // (2^256 - 1) / 3 = 0x5555...55
+        assert_eq!(PuzzleType::COMP.target(&3), [0x55; 32]);
+
+        let t_5000 = PuzzleType::COMP.target(&5000);
+        let t_6000 = PuzzleType::COMP.target(&6000);
+        assert!(t_6000 < t_5000);
+        assert!(meets_target(&t_5000, &t_5000));
+        assert!(!meets_target(&t_5000, &t_6000));
+
+        assert!(!PuzzleType::COMP.valid_difficulty(&0));
+        assert!(!PuzzleType::ZEROS.valid_difficulty(&257));
+    }
+}
@@ -0,0 +1,73 @@
+use crossbeam::channel::Sender;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+use std::thread;
+
+use crate::error::Error;
+
+// process signals are forwarded to the main loop, which acts on them between commands:
+//   SIGHUP: reload the setup config
+//   SIGUSR1: log a status snapshot
+//   SIGTERM, SIGINT: graceful shutdown. another one during shutdown forces the exit.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AppSignal {
+    Reload,
+    Status,
+    Shutdown,
+}
+
+pub fn to_app_signal(signal: i32) -> Option<AppSignal> {
+    match signal {
+        SIGHUP => Some(AppSignal::Reload),
+        SIGUSR1 => Some(AppSignal::Status),
+        SIGTERM | SIGINT => Some(AppSignal::Shutdown),
+        _ => None,
+    }
+}
+
+// registers the handlers and forwards signals until the receiver is gone
+pub fn spawn_signal_handler(tx: Sender<AppSignal>) -> Result<thread::JoinHandle<()>, Error> {
+    spawn_forwarder(&[SIGHUP, SIGUSR1, SIGTERM, SIGINT], tx)
+}
+
+fn spawn_forwarder(signals: &[i32], tx: Sender<AppSignal>) -> Result<thread::JoinHandle<()>, Error> {
+    let mut signals = match Signals::new(signals) {
+        Ok(s) => s,
+        Err(e) => { return Err(Error::io("can't register signal handlers", e)); }
+    };
+    let handle = thread::Builder::new().name("signals".to_string()).spawn(move || {
+        for signal in signals.forever() {
+            if let Some(s) = to_app_signal(signal) {
+                if tx.send(s).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    match handle {
+        Ok(h) => Ok(h),
+        Err(e) => Err(Error::io("can't spawn signal thread", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn signal_test() {
+        assert_eq!(to_app_signal(SIGTERM), Some(AppSignal::Shutdown));
+        assert_eq!(to_app_signal(SIGINT), Some(AppSignal::Shutdown));
+        assert_eq!(to_app_signal(signal_hook::consts::SIGUSR2), None);
+
+        // sigterm and sigint stay with the test harness so ctrl-c still stops it
+        let (tx, rx) = crossbeam::channel::unbounded();
+        spawn_forwarder(&[SIGHUP, SIGUSR1], tx).unwrap();
+        signal_hook::low_level::raise(SIGUSR1).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(AppSignal::Status));
+        signal_hook::low_level::raise(SIGHUP).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(AppSignal::Reload));
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::Relaxed};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::cfg_handler::{RandomxCfg, RXThreading};
+use crate::keyblock::KeyBlockSchedule;
+use crate::metrics::Metrics;
+use crate::nonce::NonceLeaser;
+use crate::pow::SharedContexts;
+use crate::precompute::Precomputed;
+use crate::puzzle::PuzzleType;
+
+// state shared between a controller, its randomx threads and workers. each pool gets its own so pools
+// and tests don't step on each other, down to the nonce state file, shared and precomputed datasets and
+// metrics.
+// only the operator keypair is still process-wide.
+
+pub type SharedState = Arc<PowState>;
+
+pub struct PowState {
+    pub max_thread_count: AtomicU32,
+    pub alloc_thread_count: AtomicU32,
+
+    pub current_keyblock: AtomicU64,
+    pub current_block_height: AtomicU64,
+    pub key_block_schedule: KeyBlockSchedule,
+
+    pub puzzle_difficulty: AtomicU32,
+    pub puzzle_type: RwLock<PuzzleType>,
+    pub shared_dataset: AtomicBool,
+    // light mode datasets are way slower to hash with but cheap to build, e.g., for tests
+    pub light_datasets: AtomicBool,
+
+    pub app_exit: AtomicBool,
+    pub randomx_restart: AtomicBool,
+    // threads switch to the new key block themselves, using precomputed datasets, instead of being restarted
+    pub hot_swap: AtomicBool,
+
+    pub nonce_leases: NonceLeaser,
+    pub precompute: Precomputed,
+    pub shared_contexts: SharedContexts,
+    pub metrics: Arc<Metrics>,
+}
+
+impl PowState {
+    pub fn new(nonce_state_path: &str) -> Self {
+        PowState {
+            max_thread_count: AtomicU32::new(0),
+            alloc_thread_count: AtomicU32::new(0),
+            current_keyblock: AtomicU64::new(1),
+            current_block_height: AtomicU64::new(0),
+            key_block_schedule: KeyBlockSchedule::default(),
+            puzzle_difficulty: AtomicU32::new(100),
+            puzzle_type: RwLock::new(PuzzleType::ZEROS),
+            shared_dataset: AtomicBool::new(false),
+            light_datasets: AtomicBool::new(false),
+            app_exit: AtomicBool::new(false),
+            randomx_restart: AtomicBool::new(false),
+            hot_swap: AtomicBool::new(false),
+            nonce_leases: NonceLeaser::load(nonce_state_path),
+            precompute: Precomputed::default(),
+            shared_contexts: Mutex::new(HashMap::new()),
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+
+    // max_threads is the number of compute units
+    pub fn from_cfg(cfg: &RandomxCfg, max_threads: u32, deallocated_threads: u32, nonce_state_path: &str) -> Self {
+        let mut state = PowState::new(nonce_state_path);
+        state.key_block_schedule = cfg.key_block_schedule;
+        state.max_thread_count.swap(max_threads, Relaxed);
+        state.alloc_thread_count.swap(max_threads.saturating_sub(deallocated_threads), Relaxed);
+        state.puzzle_difficulty.swap(cfg.difficulty, Relaxed);
+        *state.puzzle_type.write().unwrap() = cfg.puzzle;
+        state.shared_dataset.swap(cfg.thread_model == RXThreading::MULTI, Relaxed);
+        state.hot_swap.swap(cfg.precompute.enabled, Relaxed);
+        state
+    }
+
+    pub fn exiting(&self) -> bool {
+        self.app_exit.load(Relaxed)
+    }
+
+    pub fn thread_model(&self) -> RXThreading {
+        match self.shared_dataset.load(Relaxed) {
+            true => RXThreading::MULTI,
+            false => RXThreading::SINGLE,
+        }
+    }
+}
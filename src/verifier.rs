@@ -0,0 +1,200 @@
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use rust_randomx::Context;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::cfg_handler::RXThreading;
+use crate::error::Error;
+use crate::metrics::Metrics;
+use crate::puzzle::{PuzzleSolution, SolutionStatus};
+use crate::state::SharedState;
+
+// verifies solutions off the hashing threads and the main loop. only verified solutions are persisted.
+
+// solutions waiting for the verifier. hashers drop solutions rather than block when it's full.
+pub const SOLUTION_QUEUE_SIZE: usize = 1_024;
+
+// light mode contexts take ~256 MB each. there is one per compute unit and key block with the SINGLE
+// thread model, with MULTI one per key block and we keep the previous key block's around for stragglers.
+pub fn cache_size(thread_model: RXThreading, compute_units: u32) -> usize {
+    match thread_model {
+        RXThreading::SINGLE => std::cmp::max(1, compute_units as usize),
+        RXThreading::MULTI => 2,
+    }
+}
+
+// light mode contexts keyed by signed context, tagged with their key block
+pub struct ContextCache {
+    capacity: usize,
+    contexts: HashMap<Vec<u8>, (u64, Arc<Context>)>,
+}
+
+impl ContextCache {
+    pub fn new(capacity: usize) -> Self {
+        ContextCache { capacity: std::cmp::max(1, capacity), contexts: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+
+    // follows the allocation, the oldest contexts go if it shrinks
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = std::cmp::max(1, capacity);
+        while self.contexts.len() > self.capacity {
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self.contexts.iter().min_by_key(|(_, (kb, _))| *kb).map(|(k, _)| k.clone());
+        if let Some(k) = oldest {
+            self.contexts.remove(&k);
+        }
+    }
+
+    // evicts the context with the oldest key block when full
+    pub fn get(&mut self, signed_context: &Vec<u8>, key_block: u64) -> Arc<Context> {
+        if let Some((_, context)) = self.contexts.get(signed_context) {
+            return Arc::clone(context);
+        }
+        if self.contexts.len() >= self.capacity {
+            self.evict_oldest();
+        }
+        let context = Arc::new(Context::new(signed_context, false));
+        self.contexts.insert(signed_context.clone(), (key_block, Arc::clone(&context)));
+        context
+    }
+}
+
+pub fn verify_solution(cache: &mut ContextCache, mut solution: PuzzleSolution) -> PuzzleSolution {
+    let key_block = solution.key_block;
+    solution.status = match solution.verify_with(|signed_context| cache.get(signed_context, key_block)) {
+        Ok(_) => SolutionStatus::Verified,
+        Err(e) => SolutionStatus::Rejected(e),
+    };
+    solution
+}
+
+// called with each verified solution, e.g., to persist it or hand it to the embedding node
+pub type SolutionSink = Box<dyn FnMut(&PuzzleSolution) + Send>;
+
+// writes verified solutions to out_dir, or the solution dir if not set
+pub fn file_sink(out_dir: Option<String>) -> SolutionSink {
+    Box::new(move |solution: &PuzzleSolution| {
+        if let Err(e) = solution.to_file(out_dir.as_deref()) {
+            log::error!("failed to persist verified solution: {}", e);
+        }
+    })
+}
+
+// solutions handled by a verifier over its lifetime
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct VerifierStats {
+    pub verified: u64,
+    pub rejected: u64,
+}
+
+fn process_solution(cache: &mut ContextCache, solution: PuzzleSolution, sink: &mut SolutionSink, stats: &mut VerifierStats, metrics: &Metrics) {
+    let solution = verify_solution(cache, solution);
+    match solution.status {
+        SolutionStatus::Verified => {
+            metrics.record_verification(true);
+            stats.verified += 1;
+            sink(&solution);
+        }
+        SolutionStatus::Rejected(ref e) => {
+            metrics.record_verification(false);
+            stats.rejected += 1;
+            log::warn!("rejected solution for key block {} and nonce counter {}: {}", solution.key_block, solution.nonce_counter, e);
+        }
+        SolutionStatus::Unverified => {}
+    }
+}
+
+// consumes solutions until app exit, draining whatever is still queued, and returns what it handled. the
+// cache is sized to the allocated compute units.
+pub fn spawn_verifier(rx: Receiver<PuzzleSolution>, mut sink: SolutionSink, state: SharedState) -> Result<thread::JoinHandle<VerifierStats>, Error> {
+    let handle = thread::Builder::new().name("verifier".to_string()).spawn(move || {
+        let mut cache = ContextCache::new(1);
+        let mut stats = VerifierStats::default();
+        loop {
+            match rx.recv_timeout(Duration::from_millis(200)) {
+                Ok(solution) => {
+                    cache.set_capacity(cache_size(state.thread_model(), state.alloc_thread_count.load(Relaxed)));
+                    process_solution(&mut cache, solution, &mut sink, &mut stats, &state.metrics);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if state.exiting() {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        log::info!("verifier exited.");
+        stats
+    });
+    match handle {
+        Ok(h) => Ok(h),
+        Err(e) => Err(Error::io("can't spawn verifier thread", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::puzzle::PuzzleType;
+
+    #[test]
+    fn context_cache_test() {
+        let mut cache = ContextCache::new(2);
+        let first = cache.get(&b"k1".to_vec(), 2048);
+        cache.get(&b"k2".to_vec(), 4096);
+        assert!(Arc::ptr_eq(&first, &cache.get(&b"k1".to_vec(), 2048)));
+        assert_eq!(cache.len(), 2);
+
+        // the oldest key block goes first
+        cache.get(&b"k3".to_vec(), 6144);
+        assert_eq!(cache.len(), 2);
+        assert!(!Arc::ptr_eq(&first, &cache.get(&b"k1".to_vec(), 2048)));
+
+        cache.set_capacity(1);
+        assert_eq!(cache.len(), 1);
+        assert!(!cache.is_empty());
+        assert_eq!(cache_size(RXThreading::SINGLE, 16), 16);
+        assert_eq!(cache_size(RXThreading::MULTI, 16), 2);
+    }
+
+    #[test]
+    fn rejected_solution_test() {
+        let solution = PuzzleSolution {
+            peer_id: vec![0xff, 0xfe],
+            key_block: 2048,
+            signed_context: vec![0u8; 64],
+            thread_name: b"aa".to_vec(),
+            nonce_counter: 0,
+            nonce: vec![0u8; 32],
+            signed_nonce: vec![0u8; 64],
+            hash: vec![0u8; 32],
+            difficulty: 1,
+            thread_model: RXThreading::SINGLE,
+            puzzle_type: PuzzleType::ZEROS,
+            status: SolutionStatus::Unverified,
+        };
+        assert_eq!(solution.status, SolutionStatus::Unverified);
+
+        let mut cache = ContextCache::new(1);
+        let solution = verify_solution(&mut cache, solution);
+        assert_eq!(solution.status, SolutionStatus::Rejected("peer id is not valid utf8".to_string()));
+        // no context is built for solutions failing the cheap checks
+        assert_eq!(cache.len(), 0);
+    }
+}
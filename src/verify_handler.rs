@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::puzzle::PuzzleSolution;
+use crate::verifier::ContextCache;
+
+// `verify` subcommand for third parties to check solution files. everything needed is in the file: the
+// public key is inlined in the peer id and K is rebuilt from key block and thread id.
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct FileVerdict {
+    pub file: String,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub peer_id: Option<String>,
+    pub key_block: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct VerifyReport {
+    pub files: usize,
+    pub verified: usize,
+    pub rejected: usize,
+    pub results: Vec<FileVerdict>,
+}
+
+// directories are expanded to the json files they contain, sorted for stable output
+pub fn collect_files(paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for p in paths {
+        let path = Path::new(p);
+        if path.is_dir() {
+            let entries = match fs::read_dir(path) {
+                Ok(e) => e,
+                Err(e) => { return Err(format!("can't read dir {}: {}", p, e)); }
+            };
+            let mut dir_files: Vec<PathBuf> = entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|f| f.is_file() && f.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            dir_files.sort();
+            files.extend(dir_files);
+        } else if path.is_file() {
+            files.push(path.to_path_buf());
+        } else {
+            return Err(format!("no such file or dir {}", p));
+        }
+    }
+    Ok(files)
+}
+
+fn read_solution(path: &Path) -> Result<PuzzleSolution, String> {
+    match fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string())) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!("not a puzzle solution: {}", e)),
+    }
+}
+
+pub fn verify_file(path: &Path, cache: &mut ContextCache) -> FileVerdict {
+    match read_solution(path) {
+        Ok(solution) => verify_solution(path, solution, cache),
+        Err(e) => FileVerdict { file: path.display().to_string(), ok: false, error: Some(e), peer_id: None, key_block: None },
+    }
+}
+
+fn verify_solution(path: &Path, solution: PuzzleSolution, cache: &mut ContextCache) -> FileVerdict {
+    let file = path.display().to_string();
+    let key_block = solution.key_block;
+    let result = solution.verify_with(|signed_context| cache.get(signed_context, key_block));
+    FileVerdict {
+        file,
+        ok: result.is_ok(),
+        error: result.err(),
+        peer_id: String::from_utf8(solution.peer_id.clone()).ok(),
+        key_block: Some(key_block),
+    }
+}
+
+// files are verified grouped by K so a single light mode context is built per K, however many peers and
+// compute units the files come from. results are in file order.
+pub fn verify_files(files: &[PathBuf]) -> VerifyReport {
+    let mut cache = ContextCache::new(1);
+    let mut parsed: Vec<(usize, Result<PuzzleSolution, String>)> = files.iter().map(|f| read_solution(f)).enumerate().collect();
+    parsed.sort_by(|(_, a), (_, b)| a.as_ref().map(|s| &s.signed_context).ok().cmp(&b.as_ref().map(|s| &s.signed_context).ok()));
+    let mut results: Vec<(usize, FileVerdict)> = parsed
+        .into_iter()
+        .map(|(i, solution)| {
+            let verdict = match solution {
+                Ok(s) => verify_solution(&files[i], s, &mut cache),
+                Err(e) => FileVerdict { file: files[i].display().to_string(), ok: false, error: Some(e), peer_id: None, key_block: None },
+            };
+            (i, verdict)
+        })
+        .collect();
+    results.sort_by_key(|(i, _)| *i);
+    let results: Vec<FileVerdict> = results.into_iter().map(|(_, r)| r).collect();
+    let verified = results.iter().filter(|r| r.ok).count();
+    VerifyReport { files: results.len(), verified, rejected: results.len() - verified, results }
+}
+
+// returns the process exit code: 0 if all files verify, 1 if any doesn't and 2 if files are missing or
+// there is nothing to verify
+pub fn run(json: bool, paths: &[String]) -> i32 {
+    let files = match collect_files(paths) {
+        Ok(f) if f.is_empty() => {
+            eprintln!("no solution files found in {}", paths.join(", "));
+            return 2;
+        }
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let report = verify_files(&files);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        for r in &report.results {
+            match r.error {
+                None => println!("ok     {}", r.file),
+                Some(ref e) => println!("failed {}: {}", r.file, e),
+            }
+        }
+        println!("{} files, {} verified, {} rejected", report.files, report.verified, report.rejected);
+    }
+
+    match report.rejected {
+        0 => 0,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cfg_handler::RXThreading;
+    use crate::puzzle::{self, PuzzleType, SolutionStatus};
+    use crate::{mocks, nonce, pow};
+    use rust_randomx::Context;
+    use std::sync::Arc;
+
+    #[test]
+    fn verify_dir_test() {
+        let dir = std::env::temp_dir().join(format!("randomx_verify_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.json"), "{\"not\": \"a solution\"}").unwrap();
+        fs::write(dir.join("a.json"), "garbage").unwrap();
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
+
+        let dir_arg = dir.to_str().unwrap().to_string();
+        let files = collect_files(std::slice::from_ref(&dir_arg)).unwrap();
+        assert_eq!(files, vec![dir.join("a.json"), dir.join("b.json")]);
+        assert!(collect_files(&[format!("{}/missing.json", dir_arg)]).is_err());
+
+        let report = verify_files(&files);
+        assert_eq!((report.files, report.verified, report.rejected), (2, 0, 2));
+        assert!(report.results[0].error.as_ref().unwrap().starts_with("not a puzzle solution"));
+
+        assert_eq!(run(false, std::slice::from_ref(&dir_arg)), 1);
+        assert_eq!(run(true, &[format!("{}/missing.json", dir_arg)]), 2);
+
+        // nothing to verify
+        let empty = dir.join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        assert_eq!(run(false, &[empty.to_str().unwrap().to_string()]), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let kp = crate::key_handler::decode_keypair("2m2afA9bGZTUssnChzbtVhexipe1nEQnxNiNJQukisE74vDiMoh6vRUWFcMv6xjzBfNoEV4GgrLWFeMJZQxGBYQ3").unwrap();
+        let _ = crate::KEYPAIR.set(Arc::new(kp));
+        let dir = std::env::temp_dir().join(format!("randomx_verify_round_trip_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let leaser = nonce::NonceLeaser::load(dir.join("nonces.json").to_str().unwrap());
+
+        // a solution the way the hashing threads build it
+        let key_block = 2048u64;
+        let signed_context = mocks::signer(&pow::context_hash(&key_block, "aa").to_vec());
+        let context = Arc::new(Context::new(&signed_context, false));
+        let mut hasher = pow::PipelinedHasher::new(context, nonce::NonceGenerator::new(&leaser, &crate::PEERID, "aa", key_block));
+        let target = PuzzleType::ZEROS.target(&1);
+        let (counter, input, out) = loop {
+            let (counter, input, out) = hasher.next_hash();
+            if puzzle::meets_target(out.as_ref(), &target) {
+                break (counter, input, out);
+            }
+        };
+        let solution = PuzzleSolution {
+            peer_id: crate::PEERID.as_bytes().to_vec(),
+            key_block,
+            signed_context,
+            thread_name: b"aa".to_vec(),
+            nonce_counter: counter,
+            nonce: input.to_vec(),
+            signed_nonce: mocks::signer(&input.to_vec()),
+            hash: out.as_ref().to_vec(),
+            difficulty: 1,
+            thread_model: RXThreading::SINGLE,
+            puzzle_type: PuzzleType::ZEROS,
+            status: SolutionStatus::Unverified,
+        };
+        let out_dir = dir.join("solutions");
+        fs::create_dir_all(&out_dir).unwrap();
+        solution.to_file(out_dir.to_str()).unwrap();
+
+        let out_arg = out_dir.to_str().unwrap().to_string();
+        let report = verify_files(&collect_files(std::slice::from_ref(&out_arg)).unwrap());
+        assert_eq!((report.files, report.verified, report.rejected), (1, 1, 0));
+        assert_eq!(run(true, &[out_arg]), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}